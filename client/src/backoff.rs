@@ -0,0 +1,61 @@
+use std::time::Duration;
+
+/// Exponential backoff with jitter. Each delay is drawn between half and all of a
+/// ceiling that doubles after every attempt, up to `max`, so clients dropped together
+/// do not reconnect in lockstep.
+pub struct Backoff {
+    base: Duration,
+    max: Duration,
+    ceiling: Duration,
+}
+
+impl Backoff {
+    pub fn new(base: Duration, max: Duration) -> Self {
+        Self {
+            base,
+            max,
+            ceiling: base,
+        }
+    }
+
+    pub fn next_delay(&mut self) -> Duration {
+        let ceiling = self.ceiling.as_millis() as u64;
+        self.ceiling = (self.ceiling * 2).min(self.max);
+        Duration::from_millis(rand::random_range(ceiling / 2..=ceiling))
+    }
+
+    /// Starts over from the base delay, e.g. once a connection succeeded.
+    pub fn reset(&mut self) {
+        self.ceiling = self.base;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::Backoff;
+    use std::time::Duration;
+
+    #[test]
+    fn delays_grow_up_to_max() {
+        let mut backoff = Backoff::new(Duration::from_millis(100), Duration::from_millis(400));
+        let ceilings = [100, 200, 400, 400];
+
+        for ceiling in ceilings {
+            let delay = backoff.next_delay();
+            assert!(delay >= Duration::from_millis(ceiling / 2));
+            assert!(delay <= Duration::from_millis(ceiling));
+        }
+    }
+
+    #[test]
+    fn reset_starts_over() {
+        let mut backoff = Backoff::new(Duration::from_millis(100), Duration::from_secs(10));
+        for _ in 0..5 {
+            backoff.next_delay();
+        }
+        backoff.reset();
+
+        assert!(backoff.next_delay() <= Duration::from_millis(100));
+    }
+}
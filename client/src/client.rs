@@ -1,69 +1,480 @@
-use std::process::exit;
-
+use crate::{
+    backoff::Backoff,
+    event::{Disconnect, Event, MessageKind, Rejection},
+};
 use futures::{SinkExt, StreamExt};
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 use tokio::{
     net::TcpStream,
-    sync::mpsc::{self, UnboundedSender},
+    sync::mpsc::{self, UnboundedReceiver, UnboundedSender},
 };
-use tokio_util::codec::{Framed, LinesCodec};
-use utils::message::Message;
+use tokio_rustls::{TlsConnector, client::TlsStream, rustls::pki_types::ServerName};
+use tokio_util::{codec::Framed, either::Either};
+use utils::{
+    codec::{MessageCodec, WireFormat},
+    message::Message,
+    protocol::{self, DEFAULT_MAX_LINE_LENGTH, DEFAULT_MAX_TEXT_LENGTH, PROTOCOL_VERSION},
+};
+
+/// Events from a [`ClientChat`] connection. The stream ends after the final
+/// [`Event::Disconnected`].
+pub type Events = UnboundedReceiver<Event>;
 
-type MessageType = String;
+/// Size limits a [`ClientChat`] applies to its connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    /// Longest line, in bytes, read from the server.
+    pub max_line_length: usize,
+    /// Longest text, in bytes, sent to a room or a user. Longer messages are not sent and
+    /// reported as [`Rejection::TooLarge`].
+    pub max_text_length: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_line_length: DEFAULT_MAX_LINE_LENGTH,
+            max_text_length: DEFAULT_MAX_TEXT_LENGTH,
+        }
+    }
+}
 
 pub struct ClientChat {
-    sender: UnboundedSender<MessageType>,
+    /// Current name, shared with the connection task, which follows renames.
+    username: Arc<Mutex<String>>,
+    sender: UnboundedSender<Message>,
 }
 
 impl ClientChat {
-    pub async fn connect(addr: &String, username: &String) -> anyhow::Result<Self> {
+    /// Connects to `addr` and logs in, creating the account first when `register` is set.
+    /// Dropped connections are re-established in the background, resuming the session
+    /// when the server still holds it.
+    pub async fn connect(
+        addr: &str,
+        username: &str,
+        password: &str,
+        register: bool,
+        limits: Limits,
+    ) -> anyhow::Result<(Self, Events)> {
+        let transport = Transport::Plain;
+        let stream = transport.open(addr).await?;
+        Ok(Self::start(
+            addr, transport, stream, username, password, register, limits,
+        ))
+    }
+
+    /// Like [`ClientChat::connect`], but over TLS, checking the server certificate
+    /// against `server_name`.
+    pub async fn connect_tls(
+        addr: &str,
+        server_name: &str,
+        connector: &TlsConnector,
+        username: &str,
+        password: &str,
+        register: bool,
+        limits: Limits,
+    ) -> anyhow::Result<(Self, Events)> {
+        let transport = Transport::Tls {
+            connector: connector.clone(),
+            server_name: ServerName::try_from(server_name.to_string())?,
+        };
+        let stream = transport.open(addr).await?;
+        Ok(Self::start(
+            addr, transport, stream, username, password, register, limits,
+        ))
+    }
+
+    fn start(
+        addr: &str,
+        transport: Transport,
+        stream: Stream,
+        username: &str,
+        password: &str,
+        register: bool,
+        limits: Limits,
+    ) -> (Self, Events) {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let (events, events_rx) = mpsc::unbounded_channel();
+        let nickname = Arc::new(Mutex::new(username.to_string()));
+        let login = Login {
+            username: username.to_string(),
+            nickname: Arc::clone(&nickname),
+            password: password.to_string(),
+            register,
+            token: None,
+            reconnecting: false,
+            limits,
+        };
+        tokio::spawn(run(
+            addr.to_string(),
+            transport,
+            stream,
+            login,
+            receiver,
+            events,
+        ));
+        let client = Self {
+            username: nickname,
+            sender,
+        };
+        (client, events_rx)
+    }
+
+    /// Sends `text` to the current room.
+    pub fn send_message(&self, text: &str) {
+        self.send(Message::MSG(self.username(), text.to_string()));
+    }
+
+    /// Tells the room what the user is doing, e.g. `waves`.
+    pub fn send_action(&self, action: &str) {
+        let text = format!("{}{}", ACTION_PREFIX, action);
+        self.send(Message::MSG(self.username(), text));
+    }
+
+    /// Sends `text` to `username` only.
+    pub fn send_private(&self, username: &str, text: &str) {
+        self.send(Message::PRIVATE_MSG(username.to_string(), text.to_string()));
+    }
+
+    pub fn join_room(&self, room: &str) {
+        self.send(Message::JOIN_ROOM(self.username(), room.to_string()));
+    }
+
+    pub fn part_room(&self, room: &str) {
+        self.send(Message::PART_ROOM(self.username(), room.to_string()));
+    }
+
+    /// Asks for the room list, answered with [`Event::Rooms`].
+    pub fn list_rooms(&self) {
+        self.send(Message::LIST_ROOMS(vec![]));
+    }
+
+    /// Asks who is in the current room, answered with [`Event::Roster`].
+    pub fn who(&self) {
+        self.send(Message::ROSTER(vec![]));
+    }
+
+    /// Asks for an older page of the room's history; page 1 is the newest.
+    pub fn history(&self, page: u32) {
+        self.send(Message::HISTORY(page));
+    }
+
+    /// Leaves the chat. The event stream ends with [`Disconnect::Left`] once the server
+    /// has been told.
+    pub fn leave(&self) {
+        self.send(Message::LEAVE(self.username()));
+    }
+
+    /// Asks to be known as `nickname` from now on. Confirmed with [`Event::Renamed`], or
+    /// refused with [`Rejection::NicknameTaken`].
+    pub fn nick(&self, nickname: &str) {
+        self.send(Message::NICK(self.username(), nickname.to_string()));
+    }
+
+    /// The name the user is currently known by.
+    pub fn username(&self) -> String {
+        self.username.lock().unwrap().clone()
+    }
+
+    fn send(&self, message: Message) {
+        let _ = self.sender.send(message);
+    }
+}
+
+/// Marks a room message as an action. It travels as ordinary text, so clients that do
+/// not know the convention still show something sensible.
+const ACTION_PREFIX: &str = "/me ";
+
+/// Delay before the first reconnect attempt; doubles on every failure.
+const RECONNECT_BASE: Duration = Duration::from_millis(250);
+
+/// Longest delay between reconnect attempts.
+const RECONNECT_MAX: Duration = Duration::from_secs(30);
+
+type Stream = Either<TcpStream, TlsStream<TcpStream>>;
+
+/// How to reach the server again after the connection drops.
+enum Transport {
+    Plain,
+    Tls {
+        connector: TlsConnector,
+        server_name: ServerName<'static>,
+    },
+}
+
+impl Transport {
+    async fn open(&self, addr: &str) -> anyhow::Result<Stream> {
         let stream = TcpStream::connect(addr).await?;
-        let framed = Framed::new(stream, LinesCodec::new());
-        let (mut writer, mut reader) = framed.split();
+        Ok(match self {
+            Transport::Plain => Either::Left(stream),
+            Transport::Tls {
+                connector,
+                server_name,
+            } => Either::Right(connector.connect(server_name.clone(), stream).await?),
+        })
+    }
+}
+
+struct Login {
+    /// Account name, used for password logins.
+    username: String,
+    /// Current name, which differs from the account after a rename.
+    nickname: Arc<Mutex<String>>,
+    password: String,
+    register: bool,
+    /// Resume token from the server's last `SESSION`, if any.
+    token: Option<String>,
+    /// Set after a dropped connection until the server accepts a login again.
+    reconnecting: bool,
+    limits: Limits,
+}
+
+impl Login {
+    fn message(&self) -> Message {
+        match &self.token {
+            Some(token) => Message::RESUME(self.nickname.lock().unwrap().clone(), token.clone()),
+            None if self.register => {
+                Message::REGISTER(self.username.clone(), self.password.clone())
+            }
+            None => Message::AUTH(self.username.clone(), self.password.clone()),
+        }
+    }
+}
 
-        let (sender, mut receiver) = mpsc::unbounded_channel();
+/// Why a connection ended.
+enum Ended {
+    /// The user left or the server closed the session; do not reconnect.
+    Closed(Disconnect),
+    /// The server no longer knows the resume token; log in again straight away.
+    ResumeRejected,
+    /// The connection dropped. `established` tells whether the login had succeeded.
+    Lost { established: bool },
+}
 
-        // Writer task (outgoing messages)
-        tokio::spawn(async move {
-            while let Some(msg) = receiver.recv().await {
-                if writer.send(msg).await.is_err() {
-                    break;
+/// Keeps the user connected, reconnecting with jittered exponential backoff whenever
+/// the connection drops. Messages typed meanwhile are sent once connected again.
+async fn run(
+    addr: String,
+    transport: Transport,
+    stream: Stream,
+    mut login: Login,
+    mut outgoing: UnboundedReceiver<Message>,
+    events: UnboundedSender<Event>,
+) {
+    let mut stream = Some(stream);
+    let mut backoff = Backoff::new(RECONNECT_BASE, RECONNECT_MAX);
+    loop {
+        let opened = match stream.take() {
+            Some(stream) => Ok(stream),
+            None => transport.open(&addr).await,
+        };
+        // A failed connection attempt is retried like a dropped connection.
+        if let Ok(stream) = opened {
+            match session(stream, &mut login, &mut outgoing, &events).await {
+                Ended::Closed(reason) => {
+                    let _ = events.send(Event::Disconnected(reason));
+                    return;
+                }
+                Ended::ResumeRejected => continue,
+                Ended::Lost { established } => {
+                    if established {
+                        backoff.reset();
+                        login.reconnecting = true;
+                        let _ = events.send(Event::Disconnected(Disconnect::Lost));
+                    }
                 }
             }
-        });
-
-        // Reader task (incoming messages)
-        tokio::spawn(async move {
-            while let Some(Ok(line)) = reader.next().await {
-                match Message::from(line) {
-                    Message::JOIN(username) => {
-                        eprintln!("{} joined", username);
+        }
+
+        tokio::time::sleep(backoff.next_delay()).await;
+    }
+}
+
+/// Logs in over `stream` and relays messages until the connection ends.
+async fn session(
+    stream: Stream,
+    login: &mut Login,
+    outgoing: &mut UnboundedReceiver<Message>,
+    events: &UnboundedSender<Event>,
+) -> Ended {
+    let codec = MessageCodec::with_max_length(WireFormat::Pipe, login.limits.max_line_length);
+    let framed = Framed::new(stream, codec);
+    let (mut writer, mut reader) = framed.split();
+
+    let resuming = login.token.is_some();
+    let hello = Message::HELLO(PROTOCOL_VERSION, protocol::capabilities());
+    if writer.send(hello).await.is_err() || writer.send(login.message()).await.is_err() {
+        return Ended::Lost { established: false };
+    }
+    // The account exists from here on; later logins must not register it again.
+    login.register = false;
+    if !resuming {
+        // A password login starts over under the account name.
+        *login.nickname.lock().unwrap() = login.username.clone();
+    }
+
+    let mut established = false;
+    loop {
+        tokio::select! {
+            message = reader.next() => {
+                let Some(Ok(message)) = message else {
+                    return Ended::Lost { established };
+                };
+                match message {
+                    Message::PING => {
+                        let _ = writer.send(Message::PONG).await;
                     }
-                    Message::MSG(username, msg) => {
-                        eprintln!("{} : {}", username, msg);
+                    Message::SESSION(token) => {
+                        login.token = Some(token);
+                        logged_in(&mut established, login, events);
                     }
-                    Message::LEAVE(username) => {
-                        eprintln!("{} left", username);
+                    Message::ROSTER(members) => {
+                        logged_in(&mut established, login, events);
+                        let _ = events.send(Event::Roster(members));
                     }
-                    Message::ALREADYTAKEN => {
-                        eprintln!("Username is not available");
-                        exit(0);
+                    // Once logged in, a taken name can only be the answer to `NICK`.
+                    Message::ALREADYTAKEN if established => {
+                        let _ = events.send(Event::Rejected(Rejection::NicknameTaken));
                     }
-                    Message::UNAUTHENTICATED => {
-                        eprintln!("UNAUTHENTICATED");
-                        exit(0);
+                    Message::NICK(old, new) => {
+                        let mut nickname = login.nickname.lock().unwrap();
+                        if *nickname == old {
+                            *nickname = new.clone();
+                        }
+                        let _ = events.send(Event::Renamed { from: old, to: new });
+                    }
+                    Message::UNAUTHENTICATED if resuming => {
+                        login.token = None;
+                        return Ended::ResumeRejected;
+                    }
+                    Message::SHUTDOWN(reason) => {
+                        return Ended::Closed(Disconnect::Shutdown(reason));
+                    }
+                    message => {
+                        if let Some(event) = event(message) {
+                            let login_failed = matches!(
+                                &event,
+                                Event::Rejected(rejection) if rejection.ends_login()
+                            );
+                            let _ = events.send(event);
+                            if login_failed {
+                                return Ended::Closed(Disconnect::Rejected);
+                            }
+                        }
                     }
-                    _ => {}
                 }
-
-                // println!("{}", line);
             }
-        });
+            message = outgoing.recv() => {
+                let Some(message) = message else {
+                    return Ended::Closed(Disconnect::Left);
+                };
+                if is_too_long(&message, login.limits.max_text_length) {
+                    let _ = events.send(Event::Rejected(Rejection::TooLarge));
+                    continue;
+                }
+                let leaving = matches!(message, Message::LEAVE(_));
+                if writer.send(message).await.is_err() {
+                    return Ended::Lost { established };
+                }
+                if leaving {
+                    return Ended::Closed(Disconnect::Left);
+                }
+            }
+        }
+    }
+}
 
-        let _ = sender.send(Message::AUTH(username.clone()).to_string());
-        Ok(Self { sender })
+/// Whether `message` carries text longer than `max_text`.
+fn is_too_long(message: &Message, max_text: usize) -> bool {
+    match message {
+        Message::MSG(_, text) | Message::PRIVATE_MSG(_, text) => text.len() > max_text,
+        _ => false,
     }
+}
 
-    pub fn send(&self, message: String) {
-        let _ = self.sender.send(message);
+/// Notes that the server accepted the login, reporting a successful reconnect.
+fn logged_in(established: &mut bool, login: &mut Login, events: &UnboundedSender<Event>) {
+    if !*established && login.reconnecting {
+        let _ = events.send(Event::Reconnected);
+        login.reconnecting = false;
+    }
+    *established = true;
+}
+
+/// Translates a message from the server into the event it reports, if any.
+fn event(message: Message) -> Option<Event> {
+    let message_event = |from, text, kind| Event::Message { from, text, kind };
+    Some(match message {
+        Message::JOIN(username) => Event::Joined(username),
+        Message::MSG(username, text) => match text.strip_prefix(ACTION_PREFIX) {
+            Some(action) => message_event(username, action.to_string(), MessageKind::Action),
+            None => message_event(username, text, MessageKind::Room),
+        },
+        Message::LEAVE(username) => Event::Left(username),
+        Message::JOIN_ROOM(_, room) => Event::RoomJoined(room),
+        Message::PART_ROOM(_, room) => Event::RoomParted(room),
+        Message::LIST_ROOMS(rooms) => Event::Rooms(rooms),
+        Message::PRIVATE_MSG(username, text) => message_event(username, text, MessageKind::Private),
+        Message::HISTORY_MSG(username, text) => message_event(username, text, MessageKind::History),
+        Message::OFFLINE(username) => Event::Rejected(Rejection::Offline(username)),
+        Message::IMPERSONATION(username) => Event::Rejected(Rejection::Impersonation(username)),
+        Message::INVALID => Event::Rejected(Rejection::Invalid),
+        Message::RATE_LIMITED => Event::Rejected(Rejection::RateLimited),
+        Message::TOO_LARGE => Event::Rejected(Rejection::TooLarge),
+        Message::ALREADYTAKEN => Event::Rejected(Rejection::UsernameTaken),
+        Message::UNAUTHENTICATED => Event::Rejected(Rejection::Unauthenticated),
+        Message::NO_ACCOUNT => Event::Rejected(Rejection::NoAccount),
+        Message::BAD_PASSWORD => Event::Rejected(Rejection::BadPassword),
+        Message::ACCOUNT_EXISTS => Event::Rejected(Rejection::AccountExists),
+        Message::INCOMPATIBLE(min, max) => Event::Rejected(Rejection::Incompatible { min, max }),
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::event;
+    use crate::event::{Event, MessageKind, Rejection};
+    use utils::message::Message;
+
+    #[test]
+    fn messages_become_events() {
+        let msg = Message::MSG("alice".to_string(), "hi".to_string());
+        assert_eq!(
+            event(msg),
+            Some(Event::Message {
+                from: "alice".to_string(),
+                text: "hi".to_string(),
+                kind: MessageKind::Room,
+            })
+        );
+        let action = Message::MSG("alice".to_string(), "/me waves".to_string());
+        assert_eq!(
+            event(action),
+            Some(Event::Message {
+                from: "alice".to_string(),
+                text: "waves".to_string(),
+                kind: MessageKind::Action,
+            })
+        );
+        assert_eq!(
+            event(Message::ALREADYTAKEN),
+            Some(Event::Rejected(Rejection::UsernameTaken))
+        );
+        assert_eq!(
+            event(Message::TOO_LARGE),
+            Some(Event::Rejected(Rejection::TooLarge))
+        );
+        assert_eq!(event(Message::PONG), None);
+    }
+
+    #[test]
+    fn only_login_failures_end_login() {
+        assert!(Rejection::BadPassword.ends_login());
+        assert!(!Rejection::Offline("bob".to_string()).ends_login());
     }
 }
@@ -0,0 +1,269 @@
+/// A line typed by the user: plain text is sent to the room, `/name args` runs a command.
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    Send(String),
+    Action(String),
+    Private(String, String),
+    JoinRoom(String),
+    PartRoom(String),
+    ListRooms,
+    Who,
+    Nick(String),
+    History(u32),
+    Help(Option<String>),
+    Quit,
+}
+
+struct Spec {
+    name: &'static str,
+    usage: &'static str,
+    about: &'static str,
+}
+
+const COMMANDS: &[Spec] = &[
+    Spec {
+        name: "msg",
+        usage: "/msg <user> <message>",
+        about: "Send a message to one user",
+    },
+    Spec {
+        name: "me",
+        usage: "/me <action>",
+        about: "Tell the room what you are doing",
+    },
+    Spec {
+        name: "join",
+        usage: "/join <room>",
+        about: "Switch to a room, creating it if needed",
+    },
+    Spec {
+        name: "part",
+        usage: "/part <room>",
+        about: "Leave a room for the default one",
+    },
+    Spec {
+        name: "rooms",
+        usage: "/rooms",
+        about: "List the rooms",
+    },
+    Spec {
+        name: "who",
+        usage: "/who",
+        about: "List who is in the current room",
+    },
+    Spec {
+        name: "nick",
+        usage: "/nick <name>",
+        about: "Change your username",
+    },
+    Spec {
+        name: "history",
+        usage: "/history <page>",
+        about: "Fetch older messages; page 1 is the newest",
+    },
+    Spec {
+        name: "help",
+        usage: "/help [command]",
+        about: "List the commands or explain one",
+    },
+    Spec {
+        name: "quit",
+        usage: "/quit",
+        about: "Leave the chat",
+    },
+];
+
+impl Command {
+    /// Parses a line of input. Returns `None` for a blank line and a message for the user
+    /// when the line is not a valid command.
+    pub fn parse(input: &str) -> Result<Option<Command>, String> {
+        let input = input.trim();
+        if input.is_empty() {
+            return Ok(None);
+        }
+        // A doubled slash sends text that starts with one.
+        if input.starts_with("//") {
+            return Ok(Some(Command::Send(input[1..].to_string())));
+        }
+        let Some(line) = input.strip_prefix('/') else {
+            return Ok(Some(Command::Send(input.to_string())));
+        };
+
+        let (name, args) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        let args = args.trim();
+        let Some(spec) = COMMANDS.iter().find(|spec| spec.name == name) else {
+            return Err(format!("Unknown command /{}; type /help for a list", name));
+        };
+        let usage = || format!("Usage: {}", spec.usage);
+        let word = || match args {
+            "" => Err(usage()),
+            word if word.contains(char::is_whitespace) => Err(usage()),
+            word => Ok(word.to_string()),
+        };
+        let nothing = |command| match args {
+            "" => Ok(command),
+            _ => Err(usage()),
+        };
+
+        let command = match name {
+            "msg" => match args.split_once(char::is_whitespace) {
+                Some((username, text)) => {
+                    Command::Private(username.to_string(), text.trim_start().to_string())
+                }
+                None => return Err(usage()),
+            },
+            "me" if args.is_empty() => return Err(usage()),
+            "me" => Command::Action(args.to_string()),
+            "join" => Command::JoinRoom(word()?),
+            "part" => Command::PartRoom(word()?),
+            "rooms" => nothing(Command::ListRooms)?,
+            "who" => nothing(Command::Who)?,
+            "nick" => Command::Nick(word()?),
+            "history" => match args.parse() {
+                Ok(page) if page > 0 => Command::History(page),
+                _ => return Err(usage()),
+            },
+            "help" if args.is_empty() => Command::Help(None),
+            "help" => {
+                let topic = word()?;
+                let topic = topic.trim_start_matches('/');
+                if !COMMANDS.iter().any(|spec| spec.name == topic) {
+                    return Err(format!("Unknown command /{}; type /help for a list", topic));
+                }
+                Command::Help(Some(topic.to_string()))
+            }
+            "quit" => nothing(Command::Quit)?,
+            _ => unreachable!("every command in COMMANDS is parsed"),
+        };
+        Ok(Some(command))
+    }
+}
+
+/// Lines explaining the commands, or only `topic` when given.
+pub fn help(topic: Option<&str>) -> Vec<String> {
+    let line = |spec: &Spec| format!("  {:<22} {}", spec.usage, spec.about);
+    match topic {
+        Some(topic) => COMMANDS
+            .iter()
+            .filter(|spec| spec.name == topic)
+            .map(line)
+            .collect(),
+        None => std::iter::once("Commands:".to_string())
+            .chain(COMMANDS.iter().map(line))
+            .chain(std::iter::once(
+                "Anything else is sent to the room; start with // to send text beginning with /."
+                    .to_string(),
+            ))
+            .collect(),
+    }
+}
+
+/// Completions for the word that ends `line`: command names for a leading `/word`,
+/// usernames otherwise. Returns where the word starts, in bytes, and the candidates.
+pub fn complete<'a>(
+    line: &str,
+    usernames: impl IntoIterator<Item = &'a str>,
+) -> (usize, Vec<String>) {
+    let start = line
+        .char_indices()
+        .rev()
+        .find(|(_, c)| c.is_whitespace())
+        .map_or(0, |(index, c)| index + c.len_utf8());
+    let word = &line[start..];
+    let mut candidates: Vec<String> = if start == 0 && word.starts_with('/') {
+        COMMANDS
+            .iter()
+            .map(|spec| format!("/{}", spec.name))
+            .filter(|name| name.starts_with(word))
+            .collect()
+    } else if word.is_empty() {
+        vec![]
+    } else {
+        usernames
+            .into_iter()
+            .filter(|username| username.starts_with(word))
+            .map(str::to_string)
+            .collect()
+    };
+    candidates.sort();
+    candidates.dedup();
+    (start, candidates)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::{Command, complete, help};
+
+    fn parse(input: &str) -> Command {
+        Command::parse(input).unwrap().unwrap()
+    }
+
+    #[test]
+    fn plain_text_is_sent() {
+        assert_eq!(
+            parse("hello there"),
+            Command::Send("hello there".to_string())
+        );
+        assert_eq!(parse("//shrug"), Command::Send("/shrug".to_string()));
+        assert_eq!(Command::parse("   ").unwrap(), None);
+    }
+
+    #[test]
+    fn slash_commands() {
+        assert_eq!(
+            parse("/msg bob  psst, over here"),
+            Command::Private("bob".to_string(), "psst, over here".to_string())
+        );
+        assert_eq!(parse("/me waves"), Command::Action("waves".to_string()));
+        assert_eq!(
+            parse("/join project"),
+            Command::JoinRoom("project".to_string())
+        );
+        assert_eq!(parse("/history 2"), Command::History(2));
+        assert_eq!(parse("/who"), Command::Who);
+        assert_eq!(parse("/help /msg"), Command::Help(Some("msg".to_string())));
+        assert_eq!(parse("/quit"), Command::Quit);
+    }
+
+    #[test]
+    fn usage_errors_name_the_command() {
+        assert_eq!(
+            Command::parse("/msg bob"),
+            Err("Usage: /msg <user> <message>".to_string())
+        );
+        assert_eq!(
+            Command::parse("/history 0"),
+            Err("Usage: /history <page>".to_string())
+        );
+        assert_eq!(
+            Command::parse("/join two words"),
+            Err("Usage: /join <room>".to_string())
+        );
+        assert_eq!(
+            Command::parse("/sen hi"),
+            Err("Unknown command /sen; type /help for a list".to_string())
+        );
+    }
+
+    #[test]
+    fn help_covers_every_command() {
+        assert_eq!(help(None).len(), super::COMMANDS.len() + 2);
+        assert_eq!(help(Some("nick")).len(), 1);
+    }
+
+    #[test]
+    fn completes_commands_and_usernames() {
+        let users = ["alice", "albert", "bob"];
+        assert_eq!(
+            complete("/h", users),
+            (0, vec!["/help".to_string(), "/history".to_string()])
+        );
+        assert_eq!(complete("/msg b", users), (5, vec!["bob".to_string()]));
+        assert_eq!(
+            complete("hi al", users),
+            (3, vec!["albert".to_string(), "alice".to_string()])
+        );
+        assert_eq!(complete("hi ", users), (3, vec![]));
+    }
+}
@@ -0,0 +1,101 @@
+/// Something that happened on the connection, as reported by
+/// [`ClientChat::connect`](crate::client::ClientChat::connect).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    /// A user entered the current room.
+    Joined(String),
+    /// A chat message.
+    Message {
+        from: String,
+        text: String,
+        kind: MessageKind,
+    },
+    /// A user left the current room.
+    Left(String),
+    /// This user is now in the named room.
+    RoomJoined(String),
+    /// This user left the named room and is back in the default one.
+    RoomParted(String),
+    /// Reply to [`ClientChat::list_rooms`](crate::client::ClientChat::list_rooms).
+    Rooms(Vec<String>),
+    /// Who is in the current room, sent after login, after every room change and in reply
+    /// to [`ClientChat::who`](crate::client::ClientChat::who).
+    Roster(Vec<String>),
+    /// The server refused the login or a request. Login failures are followed by
+    /// [`Disconnect::Rejected`].
+    Rejected(Rejection),
+    /// The connection ended. Every reason but [`Disconnect::Lost`] is final and ends the
+    /// event stream.
+    Disconnected(Disconnect),
+    /// A user changed their name, possibly this one.
+    Renamed { from: String, to: String },
+    /// Logged in again after [`Disconnect::Lost`].
+    Reconnected,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessageKind {
+    /// Sent to the current room.
+    Room,
+    /// Sent to the current room with [`ClientChat::send_action`](crate::client::ClientChat::send_action).
+    Action,
+    /// Sent to this user only.
+    Private,
+    /// Replayed from the room's history.
+    History,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Rejection {
+    UsernameTaken,
+    Unauthenticated,
+    NoAccount,
+    BadPassword,
+    AccountExists,
+    /// The name asked for with [`ClientChat::nick`](crate::client::ClientChat::nick) is
+    /// in use or belongs to another account.
+    NicknameTaken,
+    /// The server speaks protocol versions `min` to `max` only.
+    Incompatible {
+        min: u16,
+        max: u16,
+    },
+    /// A private message could not be delivered because the user is not online.
+    Offline(String),
+    /// A frame claimed to come from another user.
+    Impersonation(String),
+    /// The server did not accept a request, e.g. an invalid room name.
+    Invalid,
+    /// A message was dropped for being sent too fast.
+    RateLimited,
+    /// A message was longer than the server or this client accepts and was not
+    /// delivered.
+    TooLarge,
+}
+
+impl Rejection {
+    /// Whether the server refused the login itself, ending the connection.
+    pub fn ends_login(&self) -> bool {
+        !matches!(
+            self,
+            Rejection::Offline(_)
+                | Rejection::Impersonation(_)
+                | Rejection::NicknameTaken
+                | Rejection::Invalid
+                | Rejection::RateLimited
+                | Rejection::TooLarge
+        )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Disconnect {
+    /// The user left.
+    Left,
+    /// The server is shutting down, with its reason if it gave one.
+    Shutdown(Option<String>),
+    /// The server refused the login.
+    Rejected,
+    /// The connection dropped; the client keeps trying to reconnect.
+    Lost,
+}
@@ -1 +1,3 @@
+pub mod backoff;
 pub mod client;
+pub mod event;
@@ -1,41 +1,209 @@
 use clap::Parser;
-use client::client::ClientChat;
+use client::{
+    client::{ClientChat, Events, Limits},
+    event::{Disconnect, Event, MessageKind, Rejection},
+};
+use command::Command;
+use presence::Presence;
+use std::path::PathBuf;
 use tokio::io::{self, AsyncBufReadExt};
-use utils::message::Message;
+use utils::{
+    protocol::{DEFAULT_MAX_LINE_LENGTH, DEFAULT_MAX_TEXT_LENGTH, PROTOCOL_VERSION},
+    tls,
+};
+
+mod command;
+mod presence;
+mod tui;
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     let args = Args::parse();
     let server_addr = &format!("{}:{}", args.host, args.port);
-    let client = ClientChat::connect(server_addr, &args.username).await?;
+    let limits = Limits {
+        max_line_length: args.max_line_length,
+        max_text_length: args.max_text_length,
+    };
+    let (client, mut events) = match &args.tls_ca {
+        Some(ca) => {
+            let identity = args.tls_cert.as_deref().zip(args.tls_key.as_deref());
+            let connector = tls::connector(ca, identity)?;
+            ClientChat::connect_tls(
+                server_addr,
+                &args.host,
+                &connector,
+                &args.username,
+                &args.password,
+                args.register,
+                limits,
+            )
+            .await?
+        }
+        None => {
+            ClientChat::connect(
+                server_addr,
+                &args.username,
+                &args.password,
+                args.register,
+                limits,
+            )
+            .await?
+        }
+    };
+
+    if args.tui {
+        tui::run(&client, &mut events, &args.username).await?;
+    } else {
+        prompt(&client, &mut events, &args.username).await;
+    }
+
+    // Stdin is read on a blocking thread that would keep the runtime from shutting down.
+    std::process::exit(0);
+}
 
-    // Terminal interaction.
-    println!("Enter command (send <MSG> or leave): ");
+/// Line-based interaction: commands from stdin, events printed as they arrive.
+async fn prompt(client: &ClientChat, events: &mut Events, username: &str) {
+    println!("Type a message to send it, or /help for commands:");
     let stdin = io::BufReader::new(io::stdin());
     let mut lines = stdin.lines();
+    let mut presence = Presence::new(username);
+    let mut leaving = false;
+    // The roster that follows login only updates presence; /who answers are shown.
+    let mut asked_who = 0;
 
-    while let Ok(Some(line)) = lines.next_line().await {
-        let command = Command::from_input(&line);
-
-        match command {
-            Command::Send(msg) => {
-                client.send(Message::MSG(args.username.clone(), msg).to_string());
-            }
-            Command::Leave => {
-                client.send(Message::LEAVE(args.username.clone()).to_string());
-                break;
-                // exit(0);
+    loop {
+        tokio::select! {
+            line = lines.next_line(), if !leaving => {
+                let Ok(Some(line)) = line else {
+                    break;
+                };
+                match Command::parse(&line) {
+                    Ok(Some(command)) => {
+                        // Keep printing until the client confirms LEAVE went out.
+                        leaving = command == Command::Quit;
+                        if command == Command::Who {
+                            asked_who += 1;
+                        }
+                        for line in execute(client, command) {
+                            println!("{}", line);
+                        }
+                    }
+                    Ok(None) => {}
+                    Err(usage) => println!("{}", usage),
+                }
             }
-            Command::Invalid => {
-                // Handle invalid command
-                println!(
-                    "Invalid command. Use 'send <MSG>' to send a message or 'leave' to disconnect."
-                );
+            event = events.recv() => {
+                let Some(event) = event else {
+                    break;
+                };
+                presence.on_event(&event);
+                if matches!(event, Event::Roster(_)) {
+                    if asked_who == 0 {
+                        continue;
+                    }
+                    asked_who -= 1;
+                }
+                if let Some(text) = report(&presence, &event) {
+                    if is_error(&event) {
+                        eprintln!("{}", text);
+                    } else {
+                        println!("{}", text);
+                    }
+                }
             }
         }
     }
+}
+
+/// Carries out `command`. Returns the lines to show for commands answered locally.
+fn execute(client: &ClientChat, command: Command) -> Vec<String> {
+    match command {
+        Command::Send(text) => client.send_message(&text),
+        Command::Action(action) => client.send_action(&action),
+        Command::Private(username, text) => client.send_private(&username, &text),
+        Command::JoinRoom(room) => client.join_room(&room),
+        Command::PartRoom(room) => client.part_room(&room),
+        Command::ListRooms => client.list_rooms(),
+        Command::History(page) => client.history(page),
+        Command::Quit => client.leave(),
+        Command::Who => client.who(),
+        Command::Nick(nickname) => client.nick(&nickname),
+        Command::Help(topic) => return command::help(topic.as_deref()),
+    }
+    vec![]
+}
 
-    Ok(())
+/// Whether `event` reports a problem rather than chat activity.
+fn is_error(event: &Event) -> bool {
+    matches!(
+        event,
+        Event::Rejected(_) | Event::Disconnected(Disconnect::Lost) | Event::Reconnected
+    )
+}
+
+/// Renders an event as a line of text, once `presence` has taken it into account.
+fn report(presence: &Presence, event: &Event) -> Option<String> {
+    match event {
+        Event::Roster(_) => Some(presence.who()),
+        event => describe(event),
+    }
+}
+
+/// Renders an event from the server as a line of text.
+fn describe(event: &Event) -> Option<String> {
+    Some(match event {
+        Event::Joined(username) => format!("{} joined", username),
+        Event::Message {
+            from,
+            text,
+            kind: MessageKind::Room,
+        } => format!("{} : {}", from, text),
+        Event::Message {
+            from,
+            text,
+            kind: MessageKind::Action,
+        } => format!("* {} {}", from, text),
+        Event::Message {
+            from,
+            text,
+            kind: MessageKind::Private,
+        } => format!("{} (private) : {}", from, text),
+        Event::Message {
+            from,
+            text,
+            kind: MessageKind::History,
+        } => format!("[history] {} : {}", from, text),
+        Event::Left(username) => format!("{} left", username),
+        Event::RoomJoined(room) => format!("You are now in #{}", room),
+        Event::RoomParted(room) => format!("You left #{}", room),
+        Event::Rooms(rooms) => format!("Rooms: {}", rooms.join(", ")),
+        Event::Roster(members) => format!("Here: {}", members.join(", ")),
+        Event::Rejected(rejection) => match rejection {
+            Rejection::Offline(username) => format!("{} is not online", username),
+            Rejection::Impersonation(username) => format!("Rejected: you are not {}", username),
+            Rejection::Invalid => "Request rejected by the server".to_string(),
+            Rejection::RateLimited => "Slow down: your message was not delivered".to_string(),
+            Rejection::TooLarge => "Message too long; it was not delivered".to_string(),
+            Rejection::UsernameTaken => "Username is not available".to_string(),
+            Rejection::Unauthenticated => "UNAUTHENTICATED".to_string(),
+            Rejection::NoAccount => "No such account; run with --register to create it".to_string(),
+            Rejection::BadPassword => "Wrong password".to_string(),
+            Rejection::AccountExists => "Username is already registered".to_string(),
+            Rejection::NicknameTaken => "That name is taken".to_string(),
+            Rejection::Incompatible { min, max } => format!(
+                "Server supports protocol versions {} to {}, this client speaks {}",
+                min, max, PROTOCOL_VERSION
+            ),
+        },
+        Event::Disconnected(Disconnect::Shutdown(Some(reason))) => {
+            format!("Server is shutting down: {}", reason)
+        }
+        Event::Disconnected(Disconnect::Shutdown(None)) => "Server is shutting down".to_string(),
+        Event::Disconnected(Disconnect::Lost) => "Connection lost, reconnecting".to_string(),
+        Event::Disconnected(Disconnect::Left | Disconnect::Rejected) => return None,
+        Event::Renamed { from, to } => format!("{} is now known as {}", from, to),
+        Event::Reconnected => "Reconnected".to_string(),
+    })
 }
 
 #[derive(Clone, Debug, Parser)]
@@ -47,25 +215,27 @@ struct Args {
     port: String,
     #[arg(short, long)]
     username: String,
-}
-
-#[derive(Debug)]
-enum Command {
-    Send(String),
-    Leave,
-    Invalid,
-}
-
-impl Command {
-    fn from_input(input: &str) -> Command {
-        let trimmed = input.trim();
-        if trimmed.starts_with("send ") {
-            let msg = trimmed[5..].to_string();
-            Command::Send(msg)
-        } else if trimmed == "leave" {
-            Command::Leave
-        } else {
-            Command::Invalid
-        }
-    }
+    #[arg(long, env = "CHAT_PASSWORD", hide_env_values = true)]
+    password: String,
+    /// Create the account before logging in
+    #[arg(long)]
+    register: bool,
+    /// PEM CA certificates used to verify the server; enables TLS
+    #[arg(long)]
+    tls_ca: Option<PathBuf>,
+    /// PEM client certificate, for servers that require one
+    #[arg(long, requires_all = ["tls_ca", "tls_key"])]
+    tls_cert: Option<PathBuf>,
+    /// PEM private key for --tls-cert
+    #[arg(long, requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+    /// Full-screen terminal interface instead of the line prompt
+    #[arg(long)]
+    tui: bool,
+    /// Longest line, in bytes, accepted from the server
+    #[arg(long, default_value_t = DEFAULT_MAX_LINE_LENGTH)]
+    max_line_length: usize,
+    /// Longest message text, in bytes, that will be sent
+    #[arg(long, default_value_t = DEFAULT_MAX_TEXT_LENGTH)]
+    max_text_length: usize,
 }
@@ -0,0 +1,97 @@
+use client::event::{Event, MessageKind};
+use std::collections::BTreeSet;
+use utils::protocol::DEFAULT_ROOM;
+
+/// The user's current room and who is known to be in it, followed from the events.
+pub struct Presence {
+    pub username: String,
+    pub room: String,
+    pub members: BTreeSet<String>,
+}
+
+impl Presence {
+    pub fn new(username: &str) -> Self {
+        Presence {
+            username: username.to_string(),
+            room: DEFAULT_ROOM.to_string(),
+            members: BTreeSet::from([username.to_string()]),
+        }
+    }
+
+    pub fn on_event(&mut self, event: &Event) {
+        match event {
+            Event::Joined(username) => {
+                self.members.insert(username.clone());
+            }
+            Event::Left(username) => {
+                self.members.remove(username);
+            }
+            Event::Message {
+                from,
+                kind: MessageKind::Room | MessageKind::Action,
+                ..
+            } => {
+                self.members.insert(from.clone());
+            }
+            Event::RoomJoined(room) => self.enter(room),
+            Event::RoomParted(_) => self.enter(DEFAULT_ROOM),
+            Event::Roster(members) => self.members = members.iter().cloned().collect(),
+            Event::Renamed { from, to } => {
+                if self.members.remove(from) {
+                    self.members.insert(to.clone());
+                }
+                if *from == self.username {
+                    self.username = to.clone();
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Switches to `room`. Its members follow in a roster.
+    fn enter(&mut self, room: &str) {
+        self.room = room.to_string();
+        self.members = BTreeSet::from([self.username.clone()]);
+    }
+
+    /// Who is in the room, as shown when a roster arrives.
+    pub fn who(&self) -> String {
+        let members: Vec<&str> = self.members.iter().map(String::as_str).collect();
+        format!("In #{}: {}", self.room, members.join(", "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::Presence;
+    use client::event::{Event, MessageKind};
+
+    #[test]
+    fn members_follow_room_events() {
+        let mut presence = Presence::new("alice");
+        presence.on_event(&Event::Joined("bob".to_string()));
+        presence.on_event(&Event::Message {
+            from: "carol".to_string(),
+            text: "hi".to_string(),
+            kind: MessageKind::Room,
+        });
+        presence.on_event(&Event::Left("bob".to_string()));
+        assert_eq!(presence.who(), "In #general: alice, carol");
+
+        presence.on_event(&Event::RoomJoined("project".to_string()));
+        assert_eq!(presence.who(), "In #project: alice");
+        presence.on_event(&Event::Roster(vec![
+            "alice".to_string(),
+            "dave".to_string(),
+        ]));
+        assert_eq!(presence.who(), "In #project: alice, dave");
+
+        presence.on_event(&Event::Renamed {
+            from: "alice".to_string(),
+            to: "alicia".to_string(),
+        });
+        assert_eq!(presence.username, "alicia");
+        assert_eq!(presence.who(), "In #project: alicia, dave");
+    }
+}
@@ -0,0 +1,488 @@
+use crate::{
+    command::{self, Command},
+    describe, execute,
+    presence::Presence,
+    report,
+};
+use client::{
+    client::{ClientChat, Events},
+    event::{Disconnect, Event},
+};
+use crossterm::event::{
+    Event as TermEvent, EventStream, KeyCode, KeyEvent, KeyEventKind, KeyModifiers,
+};
+use futures::StreamExt;
+use ratatui::{
+    Frame,
+    layout::{Constraint, Layout, Rect},
+    style::{Modifier, Style},
+    text::Line,
+    widgets::{Block, List, Paragraph},
+};
+use std::collections::VecDeque;
+
+/// Lines kept in the scrollback pane.
+const SCROLLBACK: usize = 1000;
+
+/// Width of the member list sidebar, borders included.
+const MEMBERS_WIDTH: u16 = 20;
+
+/// Runs the full-screen interface until the session ends.
+pub async fn run(client: &ClientChat, events: &mut Events, username: &str) -> anyhow::Result<()> {
+    let mut terminal = ratatui::try_init()?;
+    let mut app = App::new(username);
+    let mut keys = EventStream::new();
+
+    let result = loop {
+        if let Err(e) = terminal.draw(|frame| app.draw(frame)) {
+            break Err(e.into());
+        }
+        tokio::select! {
+            key = keys.next() => match key {
+                Some(Ok(TermEvent::Key(key))) if key.kind == KeyEventKind::Press => {
+                    app.on_key(key, client);
+                }
+                Some(Ok(_)) => {}
+                _ => break Ok(()),
+            },
+            event = events.recv() => match event {
+                Some(event) => app.on_event(&event),
+                None => break Ok(()),
+            },
+        }
+    };
+    ratatui::restore();
+
+    // The screen is gone; keep the reason the server ended the session visible.
+    if let Some(farewell) = app.farewell {
+        eprintln!("{}", farewell);
+    }
+    result
+}
+
+#[derive(Debug, PartialEq)]
+enum Connection {
+    Connected,
+    Reconnecting,
+    Closed,
+}
+
+struct App {
+    presence: Presence,
+    scrollback: VecDeque<String>,
+    /// How many wrapped lines the message pane is scrolled up from the bottom.
+    scroll: usize,
+    input: Input,
+    connection: Connection,
+    leaving: bool,
+    /// Why the server ended the session, shown after the screen is torn down.
+    farewell: Option<String>,
+}
+
+impl App {
+    fn new(username: &str) -> Self {
+        App {
+            presence: Presence::new(username),
+            scrollback: VecDeque::new(),
+            scroll: 0,
+            input: Input::default(),
+            connection: Connection::Connected,
+            leaving: false,
+            farewell: None,
+        }
+    }
+
+    fn push(&mut self, line: String) {
+        if self.scrollback.len() == SCROLLBACK {
+            self.scrollback.pop_front();
+        }
+        self.scrollback.push_back(line);
+    }
+
+    fn on_event(&mut self, event: &Event) {
+        self.presence.on_event(event);
+        match event {
+            Event::Rejected(rejection) if rejection.ends_login() => {
+                self.farewell = describe(event);
+            }
+            Event::Disconnected(Disconnect::Lost) => self.connection = Connection::Reconnecting,
+            Event::Disconnected(Disconnect::Shutdown(_)) => {
+                self.connection = Connection::Closed;
+                self.farewell = describe(event);
+            }
+            Event::Disconnected(_) => self.connection = Connection::Closed,
+            Event::Reconnected => self.connection = Connection::Connected,
+            _ => {}
+        }
+        if let Some(line) = report(&self.presence, event) {
+            self.push(line);
+        }
+    }
+
+    fn on_key(&mut self, key: KeyEvent, client: &ClientChat) {
+        if self.leaving {
+            return;
+        }
+        let ctrl = key.modifiers.contains(KeyModifiers::CONTROL);
+        match key.code {
+            KeyCode::Esc => self.leave(client),
+            KeyCode::Char('c' | 'd') if ctrl => self.leave(client),
+            KeyCode::Char('u') if ctrl => self.input.clear(),
+            KeyCode::Char(c) if !ctrl => self.input.insert(c),
+            KeyCode::Backspace => self.input.backspace(),
+            KeyCode::Delete => self.input.delete(),
+            KeyCode::Left => self.input.left(),
+            KeyCode::Right => self.input.right(),
+            KeyCode::Home => self.input.home(),
+            KeyCode::End => self.input.end(),
+            KeyCode::Up => self.input.previous(),
+            KeyCode::Down => self.input.next(),
+            KeyCode::PageUp => self.scroll += 10,
+            KeyCode::PageDown => self.scroll = self.scroll.saturating_sub(10),
+            KeyCode::Tab => self.complete(),
+            KeyCode::Enter => self.submit(client),
+            _ => {}
+        }
+    }
+
+    fn leave(&mut self, client: &ClientChat) {
+        client.leave();
+        self.leaving = true;
+    }
+
+    fn submit(&mut self, client: &ClientChat) {
+        self.scroll = 0;
+        let command = match Command::parse(&self.input.submit()) {
+            Ok(Some(command)) => command,
+            Ok(None) => return,
+            Err(usage) => return self.push(usage),
+        };
+        // The server does not echo our own messages back.
+        let username = &self.presence.username;
+        let echo = match &command {
+            Command::Send(text) => Some(format!("{} : {}", username, text)),
+            Command::Action(action) => Some(format!("* {} {}", username, action)),
+            Command::Private(to, text) => Some(format!("-> {} : {}", to, text)),
+            _ => None,
+        };
+        if let Some(echo) = echo {
+            self.push(echo);
+        }
+        self.leaving = command == Command::Quit;
+        for line in execute(client, command) {
+            self.push(line);
+        }
+    }
+
+    /// Completes the word before the cursor, listing the candidates when there are several.
+    fn complete(&mut self) {
+        let before: String = self.input.text.chars().take(self.input.cursor).collect();
+        let members = self.presence.members.iter().map(String::as_str);
+        let (start, candidates) = command::complete(&before, members);
+        let replacement = match candidates.as_slice() {
+            [] => return,
+            [only] => format!("{} ", only),
+            [first, rest @ ..] => {
+                let common = rest.iter().fold(first.len(), |len, candidate| {
+                    first
+                        .char_indices()
+                        .zip(candidate.chars())
+                        .take_while(|((index, a), b)| *index < len && a == b)
+                        .map(|((index, a), _)| index + a.len_utf8())
+                        .last()
+                        .unwrap_or(0)
+                });
+                self.push(candidates.join("  "));
+                first[..common].to_string()
+            }
+        };
+        let word = before[start..].chars().count();
+        self.input.replace_before_cursor(word, &replacement);
+    }
+
+    fn draw(&self, frame: &mut Frame) {
+        let [body, input, status] = Layout::vertical([
+            Constraint::Min(3),
+            Constraint::Length(3),
+            Constraint::Length(1),
+        ])
+        .areas(frame.area());
+        let [messages, members] =
+            Layout::horizontal([Constraint::Min(1), Constraint::Length(MEMBERS_WIDTH)]).areas(body);
+
+        let inner = Block::bordered().inner(messages);
+        let lines = self.visible_lines(inner.width as usize, inner.height as usize);
+        frame.render_widget(
+            Paragraph::new(lines)
+                .block(Block::bordered().title(format!(" #{} ", self.presence.room))),
+            messages,
+        );
+
+        frame.render_widget(
+            List::new(self.presence.members.iter().map(String::as_str))
+                .block(Block::bordered().title(" Members ")),
+            members,
+        );
+
+        self.draw_input(frame, input);
+
+        let connection = match self.connection {
+            Connection::Connected => "connected",
+            Connection::Reconnecting => "reconnecting…",
+            Connection::Closed => "disconnected",
+        };
+        let mut text = format!(
+            " {} | #{} | {}",
+            self.presence.username, self.presence.room, connection
+        );
+        if self.scroll > 0 {
+            text.push_str(" | scrolled up (PgDn to return)");
+        }
+        frame.render_widget(
+            Paragraph::new(text).style(Style::new().add_modifier(Modifier::REVERSED)),
+            status,
+        );
+    }
+
+    fn draw_input(&self, frame: &mut Frame, area: Rect) {
+        let width = area.width.saturating_sub(2) as usize;
+        // Scroll horizontally so the cursor stays in view.
+        let offset = self.input.cursor.saturating_sub(width.saturating_sub(1));
+        let shown: String = self.input.text.chars().skip(offset).take(width).collect();
+        frame.render_widget(
+            Paragraph::new(shown).block(Block::bordered().title(" Message ")),
+            area,
+        );
+        frame.set_cursor_position((area.x + 1 + (self.input.cursor - offset) as u16, area.y + 1));
+    }
+
+    /// The scrollback wrapped to `width` columns, cut to the `height` rows in view.
+    fn visible_lines(&self, width: usize, height: usize) -> Vec<Line<'static>> {
+        if width == 0 {
+            return vec![];
+        }
+        let mut rows = Vec::new();
+        for line in self.scrollback.iter().rev() {
+            let chars: Vec<char> = line.chars().collect();
+            let wrapped: Vec<String> = if chars.is_empty() {
+                vec![String::new()]
+            } else {
+                chars.chunks(width).map(|c| c.iter().collect()).collect()
+            };
+            rows.extend(wrapped.into_iter().rev());
+            if rows.len() >= height + self.scroll {
+                break;
+            }
+        }
+        let skip = self.scroll.min(rows.len().saturating_sub(height));
+        let mut visible: Vec<Line> = rows
+            .into_iter()
+            .skip(skip)
+            .take(height)
+            .map(Line::from)
+            .collect();
+        visible.reverse();
+        visible
+    }
+}
+
+/// The input line, with a cursor and a history of submitted lines.
+#[derive(Default)]
+struct Input {
+    text: String,
+    /// Cursor position, in characters.
+    cursor: usize,
+    history: Vec<String>,
+    /// Entry of `history` being shown, while browsing it.
+    browsing: Option<usize>,
+    /// What was typed before browsing started.
+    draft: String,
+}
+
+impl Input {
+    fn byte_index(&self) -> usize {
+        self.text
+            .char_indices()
+            .nth(self.cursor)
+            .map_or(self.text.len(), |(index, _)| index)
+    }
+
+    fn insert(&mut self, c: char) {
+        let index = self.byte_index();
+        self.text.insert(index, c);
+        self.cursor += 1;
+    }
+
+    fn backspace(&mut self) {
+        if self.cursor > 0 {
+            self.cursor -= 1;
+            let index = self.byte_index();
+            self.text.remove(index);
+        }
+    }
+
+    fn delete(&mut self) {
+        if self.cursor < self.text.chars().count() {
+            let index = self.byte_index();
+            self.text.remove(index);
+        }
+    }
+
+    fn left(&mut self) {
+        self.cursor = self.cursor.saturating_sub(1);
+    }
+
+    fn right(&mut self) {
+        self.cursor = (self.cursor + 1).min(self.text.chars().count());
+    }
+
+    fn home(&mut self) {
+        self.cursor = 0;
+    }
+
+    fn end(&mut self) {
+        self.cursor = self.text.chars().count();
+    }
+
+    fn clear(&mut self) {
+        self.text.clear();
+        self.cursor = 0;
+    }
+
+    /// Replaces the `count` characters before the cursor with `text`.
+    fn replace_before_cursor(&mut self, count: usize, text: &str) {
+        let end = self.byte_index();
+        self.cursor -= count;
+        let start = self.byte_index();
+        self.text.replace_range(start..end, text);
+        self.cursor += text.chars().count();
+    }
+
+    fn set(&mut self, text: String) {
+        self.text = text;
+        self.end();
+    }
+
+    /// Shows the previous history entry.
+    fn previous(&mut self) {
+        let entry = match self.browsing {
+            Some(0) => return,
+            Some(entry) => entry - 1,
+            None if self.history.is_empty() => return,
+            None => {
+                self.draft = self.text.clone();
+                self.history.len() - 1
+            }
+        };
+        self.browsing = Some(entry);
+        self.set(self.history[entry].clone());
+    }
+
+    /// Shows the next history entry, or the draft after the newest one.
+    fn next(&mut self) {
+        let Some(entry) = self.browsing else {
+            return;
+        };
+        if entry + 1 < self.history.len() {
+            self.browsing = Some(entry + 1);
+            self.set(self.history[entry + 1].clone());
+        } else {
+            self.browsing = None;
+            let draft = std::mem::take(&mut self.draft);
+            self.set(draft);
+        }
+    }
+
+    /// Takes the line, remembering it in the history.
+    fn submit(&mut self) -> String {
+        let line = std::mem::take(&mut self.text);
+        self.cursor = 0;
+        self.browsing = None;
+        if !line.trim().is_empty() && self.history.last() != Some(&line) {
+            self.history.push(line.clone());
+        }
+        line
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::{App, Connection, Input};
+    use client::event::{Disconnect, Event};
+
+    fn typed(text: &str) -> Input {
+        let mut input = Input::default();
+        text.chars().for_each(|c| input.insert(c));
+        input
+    }
+
+    #[test]
+    fn input_edits_at_cursor() {
+        let mut input = typed("héllo");
+        input.home();
+        input.right();
+        input.delete();
+        input.insert('e');
+        input.end();
+        input.backspace();
+        assert_eq!(input.text, "hell");
+        assert_eq!(input.cursor, 4);
+    }
+
+    #[test]
+    fn input_history_restores_draft() {
+        let mut input = typed("first");
+        input.submit();
+        input.set("second".to_string());
+        input.submit();
+        input.set("draft".to_string());
+
+        input.previous();
+        assert_eq!(input.text, "second");
+        input.previous();
+        input.previous();
+        assert_eq!(input.text, "first");
+        input.next();
+        input.next();
+        assert_eq!(input.text, "draft");
+    }
+
+    #[test]
+    fn tab_completes_usernames() {
+        let mut app = App::new("alice");
+        app.on_event(&Event::Joined("albert".to_string()));
+        app.on_event(&Event::Joined("bob".to_string()));
+
+        app.input = typed("/msg b");
+        app.complete();
+        assert_eq!(app.input.text, "/msg bob ");
+
+        app.input = typed("hi a");
+        app.complete();
+        assert_eq!(app.input.text, "hi al");
+        assert_eq!(app.scrollback.back().unwrap(), "albert  alice");
+    }
+
+    #[test]
+    fn status_tracks_connection() {
+        let mut app = App::new("alice");
+        app.on_event(&Event::Disconnected(Disconnect::Lost));
+        assert_eq!(app.connection, Connection::Reconnecting);
+        app.on_event(&Event::Reconnected);
+        assert_eq!(app.connection, Connection::Connected);
+    }
+
+    #[test]
+    fn long_lines_wrap_to_the_pane() {
+        let mut app = App::new("alice");
+        app.push("abcdef".to_string());
+        app.push("xy".to_string());
+        let lines: Vec<String> = app
+            .visible_lines(4, 2)
+            .iter()
+            .map(|line| line.to_string())
+            .collect();
+        assert_eq!(lines, ["ef", "xy"]);
+    }
+}
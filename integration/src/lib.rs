@@ -1,18 +1,29 @@
 //! Integration tests for the chat server and client
 #[cfg(test)]
 mod tests {
+    use rcgen::{BasicConstraints, CertificateParams, IsCa, KeyPair};
     use std::{
+        fs,
         io::{BufRead, BufReader, Read, Write},
-        net::TcpStream,
+        net::{Shutdown, TcpListener, TcpStream},
+        path::PathBuf,
         process::{Command, Stdio},
-        thread::sleep,
-        time::Duration,
+        sync::{
+            Arc, Mutex,
+            atomic::{AtomicBool, Ordering},
+            mpsc::{self, Receiver},
+        },
+        thread::{self, sleep},
+        time::{Duration, Instant},
     };
+    use tungstenite::{Message as WsMessage, WebSocket, stream::MaybeTlsStream};
 
     const TEST_HOST: &str = "127.0.0.1";
     const SERVER_BIN: &str = "../target/release/server";
     const CLIENT_BIN: &str = "../target/release/client";
+    const TEST_PASSWORD: &str = "hunter2";
     const MAX_RETRIES: u32 = 5;
+    const OUTPUT_TIMEOUT: Duration = Duration::from_secs(5);
 
     /// Helper function to wait for server to be ready
     fn wait_for_server(port: &str) -> bool {
@@ -28,6 +39,189 @@ mod tests {
         false
     }
 
+    /// Helper function to read a client's stdout or stderr on a background thread
+    fn spawn_output_reader(output: impl Read + Send + 'static) -> Receiver<String> {
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            for line in BufReader::new(output).lines().map_while(Result::ok) {
+                if tx.send(line).is_err() {
+                    break;
+                }
+            }
+        });
+        rx
+    }
+
+    /// Helper function to connect without the client binary and register with a raw frame
+    fn connect_raw(port: &str, username: &str) -> (TcpStream, Receiver<String>) {
+        let mut stream =
+            TcpStream::connect(format!("{}:{}", TEST_HOST, port)).expect("Failed to connect");
+        let output = spawn_output_reader(stream.try_clone().expect("Failed to clone stream"));
+        writeln!(stream, "{}|14|{}", username, TEST_PASSWORD).expect("Failed to register");
+        (stream, output)
+    }
+
+    /// Helper function to open a WebSocket to `port` and register with a JSON text frame
+    fn connect_websocket(port: &str, username: &str) -> WebSocket<MaybeTlsStream<TcpStream>> {
+        let (mut socket, _) = tungstenite::connect(format!("ws://{}:{}", TEST_HOST, port))
+            .expect("Failed to open WebSocket");
+        if let MaybeTlsStream::Plain(stream) = socket.get_ref() {
+            stream
+                .set_read_timeout(Some(OUTPUT_TIMEOUT))
+                .expect("Failed to set read timeout");
+        }
+        socket
+            .send(WsMessage::text(format!(
+                r#"{{"type":"REGISTER","data":["{}","{}"]}}"#,
+                username, TEST_PASSWORD
+            )))
+            .expect("Failed to register");
+        socket
+    }
+
+    /// Helper function to collect text frames until one contains `pattern` or a read times out
+    fn read_frames_until(
+        socket: &mut WebSocket<MaybeTlsStream<TcpStream>>,
+        pattern: &str,
+    ) -> String {
+        let mut frames = Vec::new();
+        while let Ok(frame) = socket.read() {
+            if let WsMessage::Text(text) = frame {
+                let found = text.contains(pattern);
+                frames.push(text.to_string());
+                if found {
+                    break;
+                }
+            }
+        }
+        frames.join("\n")
+    }
+
+    /// Helper function to fetch `path` over HTTP and return the response body
+    fn http_get(port: &str, path: &str) -> String {
+        let mut stream =
+            TcpStream::connect(format!("{}:{}", TEST_HOST, port)).expect("Failed to connect");
+        write!(
+            stream,
+            "GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+            path, TEST_HOST
+        )
+        .expect("Failed to send request");
+        let mut response = String::new();
+        stream
+            .read_to_string(&mut response)
+            .expect("Failed to read response");
+        assert!(
+            response.starts_with("HTTP/1.1 200"),
+            "GET {} failed: {}",
+            path,
+            response
+        );
+        response
+            .split_once("\r\n\r\n")
+            .map(|(_, body)| body.to_string())
+            .unwrap_or_default()
+    }
+
+    /// Helper function to write a CA plus server and client certificates signed by it.
+    /// Returns the directory holding `ca.pem`, `server.pem`, `server.key`, `client.pem`
+    /// and `client.key`
+    fn write_test_certs(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("chat-tls-{}-{}", name, std::process::id()));
+        fs::create_dir_all(&dir).expect("Failed to create cert dir");
+
+        let ca_key = KeyPair::generate().expect("Failed to generate CA key");
+        let mut ca_params = CertificateParams::new(Vec::<String>::new()).expect("CA params");
+        ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca = ca_params.self_signed(&ca_key).expect("Failed to sign CA");
+        fs::write(dir.join("ca.pem"), ca.pem()).expect("Failed to write CA");
+
+        for (file, subject) in [("server", TEST_HOST), ("client", "client")] {
+            let key = KeyPair::generate().expect("Failed to generate key");
+            let cert = CertificateParams::new(vec![subject.to_string()])
+                .expect("Cert params")
+                .signed_by(&key, &ca, &ca_key)
+                .expect("Failed to sign cert");
+            fs::write(dir.join(format!("{}.pem", file)), cert.pem()).expect("Failed to write cert");
+            fs::write(dir.join(format!("{}.key", file)), key.serialize_pem())
+                .expect("Failed to write key");
+        }
+        dir
+    }
+
+    /// Helper function to collect output until a line contains `pattern` or the timeout expires
+    fn read_output_until(output: &Receiver<String>, pattern: &str) -> String {
+        let deadline = Instant::now() + OUTPUT_TIMEOUT;
+        let mut lines = Vec::new();
+        while let Some(remaining) = deadline.checked_duration_since(Instant::now()) {
+            match output.recv_timeout(remaining) {
+                Ok(line) => {
+                    let found = line.contains(pattern);
+                    lines.push(line);
+                    if found {
+                        break;
+                    }
+                }
+                Err(_) => break,
+            }
+        }
+        lines.join("\n")
+    }
+
+    /// TCP proxy in front of the server, used to drop connections without stopping it
+    struct Proxy {
+        connections: Arc<Mutex<Vec<TcpStream>>>,
+        paused: Arc<AtomicBool>,
+    }
+
+    impl Proxy {
+        /// Helper function to forward connections on `listen_port` to the server on `port`
+        fn start(listen_port: &str, port: &str) -> Proxy {
+            let listener = TcpListener::bind(format!("{}:{}", TEST_HOST, listen_port))
+                .expect("Failed to bind proxy");
+            let upstream = format!("{}:{}", TEST_HOST, port);
+            let connections = Arc::new(Mutex::new(Vec::new()));
+            let paused = Arc::new(AtomicBool::new(false));
+            let proxy = Proxy {
+                connections: Arc::clone(&connections),
+                paused: Arc::clone(&paused),
+            };
+            thread::spawn(move || {
+                for client in listener.incoming().flatten() {
+                    if paused.load(Ordering::SeqCst) {
+                        continue;
+                    }
+                    let Ok(server) = TcpStream::connect(&upstream) else {
+                        continue;
+                    };
+                    for (mut from, mut to) in [
+                        (client.try_clone().unwrap(), server.try_clone().unwrap()),
+                        (server.try_clone().unwrap(), client.try_clone().unwrap()),
+                    ] {
+                        thread::spawn(move || {
+                            let _ = std::io::copy(&mut from, &mut to);
+                            let _ = to.shutdown(Shutdown::Both);
+                        });
+                    }
+                    connections.lock().unwrap().extend([client, server]);
+                }
+            });
+            proxy
+        }
+
+        /// Drops every open connection and refuses new ones until `resume`
+        fn cut(&self) {
+            self.paused.store(true, Ordering::SeqCst);
+            for stream in self.connections.lock().unwrap().drain(..) {
+                let _ = stream.shutdown(Shutdown::Both);
+            }
+        }
+
+        fn resume(&self) {
+            self.paused.store(false, Ordering::SeqCst);
+        }
+    }
+
     #[test]
     fn server_starts_successfully() {
         let port = "8080";
@@ -49,6 +243,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::zombie_processes)]
     fn single_client_connects() {
         let port = "8081";
 
@@ -68,6 +263,8 @@ mod tests {
             .args(["--username", "alice"])
             .args(["--host", TEST_HOST])
             .args(["--port", port])
+            .args(["--password", TEST_PASSWORD])
+            .arg("--register")
             .stdout(Stdio::null())
             .stderr(Stdio::null())
             .spawn()
@@ -87,6 +284,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::zombie_processes)]
     fn multiple_clients_connect() {
         let port = "8082";
 
@@ -105,6 +303,8 @@ mod tests {
             .args(["--username", "alice"])
             .args(["--host", TEST_HOST])
             .args(["--port", port])
+            .args(["--password", TEST_PASSWORD])
+            .arg("--register")
             .stdout(Stdio::null())
             .stderr(Stdio::null())
             .spawn()
@@ -116,6 +316,8 @@ mod tests {
             .args(["--username", "bob"])
             .args(["--host", TEST_HOST])
             .args(["--port", port])
+            .args(["--password", TEST_PASSWORD])
+            .arg("--register")
             .stdout(Stdio::null())
             .stderr(Stdio::null())
             .spawn()
@@ -127,6 +329,8 @@ mod tests {
             .args(["--username", "charlie"])
             .args(["--host", TEST_HOST])
             .args(["--port", port])
+            .args(["--password", TEST_PASSWORD])
+            .arg("--register")
             .stdout(Stdio::null())
             .stderr(Stdio::null())
             .spawn()
@@ -156,6 +360,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::zombie_processes)]
     fn duplicate_username_rejected() {
         let port = "8083";
 
@@ -174,6 +379,8 @@ mod tests {
             .args(["--username", "alice"])
             .args(["--host", TEST_HOST])
             .args(["--port", port])
+            .args(["--password", TEST_PASSWORD])
+            .arg("--register")
             .stdout(Stdio::null())
             .stderr(Stdio::null())
             .spawn()
@@ -186,6 +393,7 @@ mod tests {
             .args(["--username", "alice"])
             .args(["--host", TEST_HOST])
             .args(["--port", port])
+            .args(["--password", TEST_PASSWORD])
             .stdout(Stdio::null())
             .stderr(Stdio::piped())
             .spawn()
@@ -216,6 +424,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::zombie_processes)]
     fn client_can_send_message() {
         let port = "8084";
 
@@ -234,6 +443,8 @@ mod tests {
             .args(["--username", "alice"])
             .args(["--host", TEST_HOST])
             .args(["--port", port])
+            .args(["--password", TEST_PASSWORD])
+            .arg("--register")
             .stdin(Stdio::piped())
             .stdout(Stdio::piped())
             .stderr(Stdio::null())
@@ -261,6 +472,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::zombie_processes)]
     fn client_disconnect_and_reconnect() {
         let port = "8085";
 
@@ -279,6 +491,8 @@ mod tests {
             .args(["--username", "alice"])
             .args(["--host", TEST_HOST])
             .args(["--port", port])
+            .args(["--password", TEST_PASSWORD])
+            .arg("--register")
             .stdout(Stdio::null())
             .stderr(Stdio::null())
             .spawn()
@@ -297,6 +511,7 @@ mod tests {
             .args(["--username", "alice"])
             .args(["--host", TEST_HOST])
             .args(["--port", port])
+            .args(["--password", TEST_PASSWORD])
             .stdout(Stdio::null())
             .stderr(Stdio::null())
             .spawn()
@@ -316,6 +531,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::zombie_processes, clippy::expect_fun_call)]
     fn rapid_client_connections() {
         let port = "8086";
 
@@ -338,6 +554,8 @@ mod tests {
                 .args(["--username", &username])
                 .args(["--host", TEST_HOST])
                 .args(["--port", port])
+                .args(["--password", TEST_PASSWORD])
+                .arg("--register")
                 .stdout(Stdio::null())
                 .stderr(Stdio::null())
                 .spawn()
@@ -366,6 +584,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::zombie_processes, clippy::manual_flatten)]
     fn broadcast_message_to_user() {
         let port = "8089";
         
@@ -384,6 +603,8 @@ mod tests {
             .args(["--username", "alice"])
             .args(["--host", TEST_HOST])
             .args(["--port", port])
+            .args(["--password", TEST_PASSWORD])
+            .arg("--register")
             .stdin(Stdio::piped())
             .stdout(Stdio::piped())
             .stderr(Stdio::null())
@@ -396,6 +617,8 @@ mod tests {
             .args(["--username", "bob"])
             .args(["--host", TEST_HOST])
             .args(["--port", port])
+            .args(["--password", TEST_PASSWORD])
+            .arg("--register")
             .stdin(Stdio::piped())
             .stdout(Stdio::piped())
             .stderr(Stdio::null())
@@ -408,6 +631,8 @@ mod tests {
             .args(["--username", "charlie"])
             .args(["--host", TEST_HOST])
             .args(["--port", port])
+            .args(["--password", TEST_PASSWORD])
+            .arg("--register")
             .stdin(Stdio::piped())
             .stdout(Stdio::piped())
             .stderr(Stdio::null())
@@ -454,4 +679,1616 @@ mod tests {
         client3.kill().expect("Failed to kill client3");
         server.kill().expect("Failed to kill server");
     }
+
+    #[test]
+    fn messages_stay_in_their_room() {
+        let port = "8090";
+
+        // Start the server
+        let mut server = Command::new(SERVER_BIN)
+            .args(["--port", port])
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("Failed to start server");
+
+        assert!(wait_for_server(port), "Server failed to start");
+
+        let mut clients = Vec::new();
+        let mut outputs = Vec::new();
+        for username in ["alice", "bob", "charlie"] {
+            let mut client = Command::new(CLIENT_BIN)
+                .args(["--username", username])
+                .args(["--host", TEST_HOST])
+                .args(["--port", port])
+                .args(["--password", TEST_PASSWORD])
+                .arg("--register")
+                .stdin(Stdio::piped())
+                .stdout(Stdio::piped())
+                .stderr(Stdio::null())
+                .spawn()
+                .unwrap_or_else(|_| panic!("Failed to start {}", username));
+            outputs.push(spawn_output_reader(
+                client.stdout.take().expect("No client stdout"),
+            ));
+            clients.push(client);
+            sleep(Duration::from_millis(300));
+        }
+
+        // Bob and charlie move to #project
+        for (i, client) in clients.iter_mut().enumerate().skip(1) {
+            let stdin = client.stdin.as_mut().expect("Failed to open stdin");
+            writeln!(stdin, "/join project").expect("Failed to write to stdin");
+            stdin.flush().expect("Failed to flush stdin");
+            let joined = read_output_until(&outputs[i], "You are now in #project");
+            assert!(
+                joined.contains("You are now in #project"),
+                "Client {} should join #project. Got: {}",
+                i,
+                joined
+            );
+        }
+
+        // Alice sees both rooms listed
+        let alice_stdin = clients[0].stdin.as_mut().expect("Failed to open stdin");
+        writeln!(alice_stdin, "/rooms").expect("Failed to write to stdin");
+        alice_stdin.flush().expect("Failed to flush stdin");
+        let rooms = read_output_until(&outputs[0], "Rooms:");
+        assert!(
+            rooms.contains("Rooms: general, project"),
+            "Alice should see both rooms. Got: {}",
+            rooms
+        );
+
+        // Charlie talks in #project, alice talks in #general
+        let charlie_stdin = clients[2].stdin.as_mut().expect("Failed to open stdin");
+        writeln!(charlie_stdin, "hello project").expect("Failed to write to stdin");
+        charlie_stdin.flush().expect("Failed to flush stdin");
+        let alice_stdin = clients[0].stdin.as_mut().expect("Failed to open stdin");
+        writeln!(alice_stdin, "hello general").expect("Failed to write to stdin");
+        alice_stdin.flush().expect("Failed to flush stdin");
+
+        let bob_received = read_output_until(&outputs[1], "hello project");
+        assert!(
+            bob_received.contains("charlie : hello project"),
+            "Bob should receive charlie's message. Got: {}",
+            bob_received
+        );
+        assert!(
+            !bob_received.contains("hello general"),
+            "Bob should not receive messages from #general. Got: {}",
+            bob_received
+        );
+
+        // Cleanup
+        for mut client in clients {
+            client.kill().expect("Failed to kill client");
+            client.wait().expect("Failed to wait for client");
+        }
+        server.kill().expect("Failed to kill server");
+        server.wait().expect("Failed to wait for server");
+    }
+
+    #[test]
+    fn private_message_reaches_only_target() {
+        let port = "8091";
+
+        // Start the server
+        let mut server = Command::new(SERVER_BIN)
+            .args(["--port", port])
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("Failed to start server");
+
+        assert!(wait_for_server(port), "Server failed to start");
+
+        let mut clients = Vec::new();
+        let mut outputs = Vec::new();
+        for username in ["alice", "bob", "charlie"] {
+            let mut client = Command::new(CLIENT_BIN)
+                .args(["--username", username])
+                .args(["--host", TEST_HOST])
+                .args(["--port", port])
+                .args(["--password", TEST_PASSWORD])
+                .arg("--register")
+                .stdin(Stdio::piped())
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped())
+                .spawn()
+                .unwrap_or_else(|_| panic!("Failed to start {}", username));
+            outputs.push(spawn_output_reader(
+                client.stdout.take().expect("No client stdout"),
+            ));
+            clients.push(client);
+            sleep(Duration::from_millis(300));
+        }
+        let alice_errors = spawn_output_reader(clients[0].stderr.take().expect("No stderr"));
+
+        // Alice whispers to bob, then to someone who is not connected
+        let alice_stdin = clients[0].stdin.as_mut().expect("Failed to open stdin");
+        writeln!(alice_stdin, "/msg bob the password is swordfish")
+            .expect("Failed to write to stdin");
+        writeln!(alice_stdin, "/msg ghost are you there?").expect("Failed to write to stdin");
+        writeln!(alice_stdin, "hello everyone").expect("Failed to write to stdin");
+        alice_stdin.flush().expect("Failed to flush stdin");
+
+        let bob_received = read_output_until(&outputs[1], "swordfish");
+        assert!(
+            bob_received.contains("alice (private) : the password is swordfish"),
+            "Bob should receive alice's private message. Got: {}",
+            bob_received
+        );
+
+        let charlie_received = read_output_until(&outputs[2], "hello everyone");
+        assert!(
+            !charlie_received.contains("swordfish"),
+            "Charlie should not receive the private message. Got: {}",
+            charlie_received
+        );
+
+        let alice_error = read_output_until(&alice_errors, "ghost");
+        assert!(
+            alice_error.contains("ghost is not online"),
+            "Alice should be told ghost is offline. Got: {}",
+            alice_error
+        );
+
+        // Cleanup
+        for mut client in clients {
+            client.kill().expect("Failed to kill client");
+            client.wait().expect("Failed to wait for client");
+        }
+        server.kill().expect("Failed to kill server");
+        server.wait().expect("Failed to wait for server");
+    }
+
+    #[test]
+    fn spoofed_sender_rejected() {
+        let port = "8092";
+
+        // Start the server
+        let mut server = Command::new(SERVER_BIN)
+            .args(["--port", port])
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("Failed to start server");
+
+        assert!(wait_for_server(port), "Server failed to start");
+
+        let (mut alice, alice_output) = connect_raw(port, "alice");
+        sleep(Duration::from_millis(500));
+        let (_bob, bob_output) = connect_raw(port, "bob");
+        let joined = read_output_until(&alice_output, "bob|3|");
+        assert!(
+            joined.contains("bob|3|"),
+            "Bob should join. Got: {}",
+            joined
+        );
+
+        // Alice pretends to be bob, both talking and leaving
+        writeln!(alice, "bob|2|I am bob").expect("Failed to write");
+        let rejected = read_output_until(&alice_output, "|13|");
+        assert!(
+            rejected.contains("bob|13|"),
+            "Spoofed MSG should be rejected. Got: {}",
+            rejected
+        );
+        writeln!(alice, "bob|4|").expect("Failed to write");
+        let rejected = read_output_until(&alice_output, "|13|");
+        assert!(
+            rejected.contains("bob|13|"),
+            "Spoofed LEAVE should be rejected. Got: {}",
+            rejected
+        );
+
+        // Frames without a username are stamped with the authenticated one
+        writeln!(alice, "|2|it was me").expect("Failed to write");
+        let bob_received = read_output_until(&bob_output, "it was me");
+        assert!(
+            bob_received.contains("alice|2|it was me"),
+            "Bob should receive alice's stamped message. Got: {}",
+            bob_received
+        );
+        assert!(
+            !bob_received.contains("I am bob") && !bob_received.contains("bob|4|"),
+            "Spoofed frames should not be delivered. Got: {}",
+            bob_received
+        );
+
+        // Cleanup
+        server.kill().expect("Failed to kill server");
+        server.wait().expect("Failed to wait for server");
+    }
+
+    #[test]
+    fn login_failures_have_distinct_replies() {
+        let port = "8093";
+
+        // Start the server
+        let mut server = Command::new(SERVER_BIN)
+            .args(["--port", port])
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("Failed to start server");
+
+        assert!(wait_for_server(port), "Server failed to start");
+
+        let (_alice, _alice_output) = connect_raw(port, "alice");
+        sleep(Duration::from_millis(500));
+
+        for (frame, reply) in [
+            ("alice|1|wrong", "|16|"),
+            ("mallory|1|hunter2", "|15|"),
+            ("alice|14|squatter", "|17|"),
+        ] {
+            let mut stream =
+                TcpStream::connect(format!("{}:{}", TEST_HOST, port)).expect("Failed to connect");
+            let output = spawn_output_reader(stream.try_clone().expect("Failed to clone stream"));
+            writeln!(stream, "{}", frame).expect("Failed to write");
+            let received = read_output_until(&output, reply);
+            assert!(
+                received.contains(reply),
+                "Expected {} for {}. Got: {}",
+                reply,
+                frame,
+                received
+            );
+        }
+
+        // Cleanup
+        server.kill().expect("Failed to kill server");
+        server.wait().expect("Failed to wait for server");
+    }
+
+    #[test]
+    fn tls_clients_can_chat() {
+        let port = "8094";
+        let certs = write_test_certs(port);
+        let path = |file: &str| certs.join(file).to_string_lossy().into_owned();
+
+        // Start the server, requiring client certificates
+        let mut server = Command::new(SERVER_BIN)
+            .args(["--port", port])
+            .args(["--tls-cert", &path("server.pem")])
+            .args(["--tls-key", &path("server.key")])
+            .args(["--tls-client-ca", &path("ca.pem")])
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("Failed to start server");
+
+        assert!(wait_for_server(port), "Server failed to start");
+
+        let mut clients = Vec::new();
+        let mut outputs = Vec::new();
+        for username in ["alice", "bob"] {
+            let mut client = Command::new(CLIENT_BIN)
+                .args(["--username", username])
+                .args(["--host", TEST_HOST])
+                .args(["--port", port])
+                .args(["--password", TEST_PASSWORD])
+                .arg("--register")
+                .args(["--tls-ca", &path("ca.pem")])
+                .args(["--tls-cert", &path("client.pem")])
+                .args(["--tls-key", &path("client.key")])
+                .stdin(Stdio::piped())
+                .stdout(Stdio::piped())
+                .stderr(Stdio::null())
+                .spawn()
+                .unwrap_or_else(|_| panic!("Failed to start {}", username));
+            outputs.push(spawn_output_reader(
+                client.stdout.take().expect("No client stdout"),
+            ));
+            clients.push(client);
+            sleep(Duration::from_millis(500));
+        }
+
+        let alice_stdin = clients[0].stdin.as_mut().expect("Failed to open stdin");
+        writeln!(alice_stdin, "hello over tls").expect("Failed to write to stdin");
+        alice_stdin.flush().expect("Failed to flush stdin");
+
+        let bob_received = read_output_until(&outputs[1], "hello over tls");
+        assert!(
+            bob_received.contains("alice : hello over tls"),
+            "Bob should receive alice's message over TLS. Got: {}",
+            bob_received
+        );
+
+        // Cleanup
+        for mut client in clients {
+            client.kill().expect("Failed to kill client");
+            client.wait().expect("Failed to wait for client");
+        }
+        server.kill().expect("Failed to kill server");
+        server.wait().expect("Failed to wait for server");
+        let _ = fs::remove_dir_all(certs);
+    }
+
+    #[test]
+    fn message_with_pipes_delivered() {
+        let port = "8095";
+
+        // Start the server
+        let mut server = Command::new(SERVER_BIN)
+            .args(["--port", port])
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("Failed to start server");
+
+        assert!(wait_for_server(port), "Server failed to start");
+
+        let (mut alice, _alice_output) = connect_raw(port, "alice");
+        sleep(Duration::from_millis(500));
+        // Bob agrees on escaped frames, carol skips the handshake
+        let mut bob =
+            TcpStream::connect(format!("{}:{}", TEST_HOST, port)).expect("Failed to connect");
+        let bob_output = spawn_output_reader(bob.try_clone().expect("Failed to clone stream"));
+        writeln!(bob, "|18|2 escaped-frames").expect("Failed to write");
+        writeln!(bob, "bob|14|{}", TEST_PASSWORD).expect("Failed to register");
+        let (_carol, carol_output) = connect_raw(port, "carol");
+        sleep(Duration::from_millis(500));
+
+        // Escaped frame carrying a pipe and an embedded newline
+        let frame = r"\alice|2|ps aux \p grep chat\nline two";
+        writeln!(alice, "{}", frame).expect("Failed to write");
+        let bob_received = read_output_until(&bob_output, "grep chat");
+        assert!(
+            bob_received.contains(frame),
+            "Bob should receive the escaped message intact. Got: {}",
+            bob_received
+        );
+        let carol_received = read_output_until(&carol_output, "grep chat");
+        assert!(
+            carol_received.contains("alice|2|ps aux ¦ grep chat line two"),
+            "Carol should receive a plain copy. Got: {}",
+            carol_received
+        );
+
+        // Cleanup
+        server.kill().expect("Failed to kill server");
+        server.wait().expect("Failed to wait for server");
+    }
+
+    #[test]
+    fn handshake_negotiates_and_refuses_legacy_clients() {
+        let port = "8096";
+
+        // Start the server, refusing clients that skip the handshake
+        let mut server = Command::new(SERVER_BIN)
+            .args(["--port", port])
+            .args(["--min-protocol-version", "2"])
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("Failed to start server");
+
+        assert!(wait_for_server(port), "Server failed to start");
+
+        // A legacy client logging in straight away is refused
+        let (_legacy, legacy_output) = connect_raw(port, "legacy");
+        let refused = read_output_until(&legacy_output, "|20|");
+        assert!(
+            refused.contains("|20|2 2"),
+            "Legacy client should be refused. Got: {}",
+            refused
+        );
+
+        // A newer client is negotiated down and keeps only known capabilities
+        let mut stream =
+            TcpStream::connect(format!("{}:{}", TEST_HOST, port)).expect("Failed to connect");
+        let output = spawn_output_reader(stream.try_clone().expect("Failed to clone stream"));
+        writeln!(stream, "|18|7 rooms,teleport").expect("Failed to write");
+        writeln!(stream, "alice|14|{}", TEST_PASSWORD).expect("Failed to write");
+        let welcome = read_output_until(&output, "|19|");
+        assert!(
+            welcome.contains("|19|2 rooms"),
+            "Client should be welcomed with protocol 2. Got: {}",
+            welcome
+        );
+
+        // The regular client performs the handshake and stays connected
+        let mut client = Command::new(CLIENT_BIN)
+            .args(["--username", "bob"])
+            .args(["--host", TEST_HOST])
+            .args(["--port", port])
+            .args(["--password", TEST_PASSWORD])
+            .arg("--register")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("Failed to start client");
+        sleep(Duration::from_secs(1));
+        assert!(
+            client.try_wait().unwrap().is_none(),
+            "Client should still be running"
+        );
+
+        // Cleanup
+        client.kill().expect("Failed to kill client");
+        client.wait().expect("Failed to wait for client");
+        server.kill().expect("Failed to kill server");
+        server.wait().expect("Failed to wait for server");
+    }
+
+    #[test]
+    fn json_and_pipe_clients_share_a_room() {
+        let port = "8097";
+        let json_port = "8098";
+
+        // Start the server with an extra JSON listener
+        let mut server = Command::new(SERVER_BIN)
+            .args(["--port", port])
+            .args(["--json-port", json_port])
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("Failed to start server");
+
+        assert!(wait_for_server(port), "Server failed to start");
+        assert!(wait_for_server(json_port), "JSON listener failed to start");
+
+        let (mut alice, alice_output) = connect_raw(port, "alice");
+        sleep(Duration::from_millis(500));
+
+        let mut bob =
+            TcpStream::connect(format!("{}:{}", TEST_HOST, json_port)).expect("Failed to connect");
+        let bob_output = spawn_output_reader(bob.try_clone().expect("Failed to clone stream"));
+        writeln!(
+            bob,
+            r#"{{"type":"REGISTER","data":["bob","{}"]}}"#,
+            TEST_PASSWORD
+        )
+        .expect("Failed to write");
+        let joined = read_output_until(&alice_output, "bob|3|");
+        assert!(
+            joined.contains("bob|3|"),
+            "Bob should join. Got: {}",
+            joined
+        );
+
+        // Pipe to JSON
+        writeln!(alice, "|2|hi from pipe").expect("Failed to write");
+        let bob_received = read_output_until(&bob_output, "hi from pipe");
+        assert!(
+            bob_received.contains(r#"{"type":"MSG","data":["alice","hi from pipe"]}"#),
+            "Bob should receive alice's message as JSON. Got: {}",
+            bob_received
+        );
+
+        // JSON to pipe
+        writeln!(bob, r#"{{"type":"MSG","data":["bob","hi from json"]}}"#)
+            .expect("Failed to write");
+        let alice_received = read_output_until(&alice_output, "hi from json");
+        assert!(
+            alice_received.contains("bob|2|hi from json"),
+            "Alice should receive bob's message as a pipe frame. Got: {}",
+            alice_received
+        );
+
+        // Cleanup
+        server.kill().expect("Failed to kill server");
+        server.wait().expect("Failed to wait for server");
+    }
+
+    #[test]
+    fn history_replayed_on_join() {
+        let port = "8099";
+
+        // Start the server, replaying two messages per page
+        let mut server = Command::new(SERVER_BIN)
+            .args(["--port", port])
+            .args(["--history-replay", "2"])
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("Failed to start server");
+
+        assert!(wait_for_server(port), "Server failed to start");
+
+        let (mut alice, _alice_output) = connect_raw(port, "alice");
+        sleep(Duration::from_millis(500));
+        for text in ["first", "second", "third"] {
+            writeln!(alice, "alice|2|{}", text).expect("Failed to write");
+        }
+        sleep(Duration::from_millis(500));
+
+        // Bob joins late and gets the latest page
+        let mut bob = Command::new(CLIENT_BIN)
+            .args(["--username", "bob"])
+            .args(["--host", TEST_HOST])
+            .args(["--port", port])
+            .args(["--password", TEST_PASSWORD])
+            .arg("--register")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("Failed to start client");
+        let bob_output = spawn_output_reader(bob.stdout.take().expect("No client stdout"));
+        let replayed = read_output_until(&bob_output, "[history] alice : third");
+        assert!(
+            replayed.contains("[history] alice : second")
+                && replayed.contains("[history] alice : third")
+                && !replayed.contains("first"),
+            "Bob should see the last two messages. Got: {}",
+            replayed
+        );
+
+        // The next page holds the older message
+        let bob_stdin = bob.stdin.as_mut().expect("Failed to open stdin");
+        writeln!(bob_stdin, "/history 2").expect("Failed to write to stdin");
+        bob_stdin.flush().expect("Failed to flush stdin");
+        let older = read_output_until(&bob_output, "first");
+        assert!(
+            older.contains("[history] alice : first"),
+            "Bob should fetch the older page. Got: {}",
+            older
+        );
+
+        // Cleanup
+        bob.kill().expect("Failed to kill client");
+        bob.wait().expect("Failed to wait for client");
+        server.kill().expect("Failed to kill server");
+        server.wait().expect("Failed to wait for server");
+    }
+
+    #[test]
+    fn clients_notified_on_shutdown() {
+        let port = "8100";
+
+        // Start the server
+        let mut server = Command::new(SERVER_BIN)
+            .args(["--port", port])
+            .args(["--shutdown-reason", "maintenance"])
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("Failed to start server");
+
+        assert!(wait_for_server(port), "Server failed to start");
+
+        let (_alice, alice_output) = connect_raw(port, "alice");
+        let mut bob = Command::new(CLIENT_BIN)
+            .args(["--username", "bob"])
+            .args(["--host", TEST_HOST])
+            .args(["--port", port])
+            .args(["--password", TEST_PASSWORD])
+            .arg("--register")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("Failed to start client");
+        let bob_output = spawn_output_reader(bob.stdout.take().expect("No client stdout"));
+        read_output_until(&alice_output, "bob|3|");
+
+        // SIGTERM stops the server after telling everyone
+        let status = Command::new("kill")
+            .args(["-TERM", &server.id().to_string()])
+            .status()
+            .expect("Failed to signal server");
+        assert!(status.success());
+
+        let notice = read_output_until(&alice_output, "|23|");
+        assert!(
+            notice.contains("|23|maintenance"),
+            "Alice should receive the shutdown notice. Got: {}",
+            notice
+        );
+        let shown = read_output_until(&bob_output, "shutting down");
+        assert!(
+            shown.contains("Server is shutting down: maintenance"),
+            "Bob should show the shutdown notice. Got: {}",
+            shown
+        );
+
+        let bob_status = bob.wait().expect("Failed to wait for client");
+        assert!(bob_status.success(), "Client should exit cleanly");
+        let server_status = server.wait().expect("Failed to wait for server");
+        assert!(server_status.success(), "Server should exit cleanly");
+    }
+
+    #[test]
+    fn silent_peer_dropped_after_idle_timeout() {
+        let port = "8101";
+
+        // Start the server with a short heartbeat
+        let mut server = Command::new(SERVER_BIN)
+            .args(["--port", port])
+            .args(["--heartbeat-interval", "1"])
+            .args(["--idle-timeout", "2"])
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("Failed to start server");
+
+        assert!(wait_for_server(port), "Server failed to start");
+
+        // Bob's client answers pings on its own
+        let mut bob = Command::new(CLIENT_BIN)
+            .args(["--username", "bob"])
+            .args(["--host", TEST_HOST])
+            .args(["--port", port])
+            .args(["--password", TEST_PASSWORD])
+            .arg("--register")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("Failed to start client");
+        let bob_output = spawn_output_reader(bob.stdout.take().expect("No client stdout"));
+        sleep(Duration::from_millis(500));
+
+        // Alice never answers
+        let (_alice, alice_output) = connect_raw(port, "alice");
+        let pinged = read_output_until(&alice_output, "|24|");
+        assert!(
+            pinged.contains("|24|"),
+            "Alice should be pinged. Got: {}",
+            pinged
+        );
+
+        let left = read_output_until(&bob_output, "alice left");
+        assert!(
+            left.contains("alice left"),
+            "Bob should see alice time out. Got: {}",
+            left
+        );
+        assert!(
+            bob.try_wait().unwrap().is_none(),
+            "Bob should still be connected"
+        );
+        let (_carol, carol_output) = connect_raw(port, "carol");
+        sleep(Duration::from_millis(500));
+        let mut bob_stdin = bob.stdin.take().expect("Failed to open stdin");
+        writeln!(bob_stdin, "still here").expect("Failed to write to stdin");
+        bob_stdin.flush().expect("Failed to flush stdin");
+        let received = read_output_until(&carol_output, "still here");
+        assert!(
+            received.contains("bob|2|still here"),
+            "Bob should still be able to chat. Got: {}",
+            received
+        );
+
+        // Cleanup
+        bob.kill().expect("Failed to kill client");
+        bob.wait().expect("Failed to wait for client");
+        server.kill().expect("Failed to kill server");
+        server.wait().expect("Failed to wait for server");
+    }
+
+    #[test]
+    fn peer_that_never_logs_in_dropped_after_idle_timeout() {
+        let port = "8118";
+
+        // Start the server with a short idle timeout
+        let mut server = Command::new(SERVER_BIN)
+            .args(["--port", port])
+            .args(["--idle-timeout", "1"])
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("Failed to start server");
+
+        assert!(wait_for_server(port), "Server failed to start");
+
+        // Connect and stay silent
+        let mut stream =
+            TcpStream::connect(format!("{}:{}", TEST_HOST, port)).expect("Failed to connect");
+        stream
+            .set_read_timeout(Some(OUTPUT_TIMEOUT))
+            .expect("Failed to set read timeout");
+        let mut received = String::new();
+        let closed = stream.read_to_string(&mut received);
+
+        assert!(
+            closed.is_ok(),
+            "Server should close the connection. Got: {:?}",
+            closed
+        );
+        assert!(
+            received.contains("|7|"),
+            "Server should answer UNAUTHENTICATED. Got: {}",
+            received
+        );
+
+        // Cleanup
+        server.kill().expect("Failed to kill server");
+        server.wait().expect("Failed to wait for server");
+    }
+
+    #[test]
+    fn websocket_peer_without_handshake_dropped_after_idle_timeout() {
+        let port = "8119";
+        let ws_port = "8120";
+
+        // Start the server with a WebSocket listener and a short idle timeout
+        let mut server = Command::new(SERVER_BIN)
+            .args(["--port", port])
+            .args(["--ws-port", ws_port])
+            .args(["--idle-timeout", "1"])
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("Failed to start server");
+
+        assert!(wait_for_server(ws_port), "Server failed to start");
+
+        // Connect and never send the upgrade request
+        let mut stream =
+            TcpStream::connect(format!("{}:{}", TEST_HOST, ws_port)).expect("Failed to connect");
+        stream
+            .set_read_timeout(Some(OUTPUT_TIMEOUT))
+            .expect("Failed to set read timeout");
+        let mut received = Vec::new();
+        let closed = stream.read_to_end(&mut received);
+
+        assert!(
+            closed.is_ok(),
+            "Server should close the connection. Got: {:?}",
+            closed
+        );
+
+        // Cleanup
+        server.kill().expect("Failed to kill server");
+        server.wait().expect("Failed to wait for server");
+    }
+
+    #[test]
+    fn peer_without_tls_handshake_dropped_after_idle_timeout() {
+        let port = "8121";
+        let certs = write_test_certs(port);
+        let path = |file: &str| certs.join(file).to_string_lossy().into_owned();
+
+        // Start a TLS server with a short idle timeout
+        let mut server = Command::new(SERVER_BIN)
+            .args(["--port", port])
+            .args(["--tls-cert", &path("server.pem")])
+            .args(["--tls-key", &path("server.key")])
+            .args(["--idle-timeout", "1"])
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("Failed to start server");
+
+        assert!(wait_for_server(port), "Server failed to start");
+
+        // Connect and never send a ClientHello
+        let mut stream =
+            TcpStream::connect(format!("{}:{}", TEST_HOST, port)).expect("Failed to connect");
+        stream
+            .set_read_timeout(Some(OUTPUT_TIMEOUT))
+            .expect("Failed to set read timeout");
+        let mut received = Vec::new();
+        let closed = stream.read_to_end(&mut received);
+
+        assert!(
+            closed.is_ok(),
+            "Server should close the connection. Got: {:?}",
+            closed
+        );
+
+        // Cleanup
+        server.kill().expect("Failed to kill server");
+        server.wait().expect("Failed to wait for server");
+        let _ = fs::remove_dir_all(certs);
+    }
+
+    #[test]
+    fn zero_heartbeat_settings_rejected() {
+        for flag in ["--heartbeat-interval", "--idle-timeout"] {
+            let status = Command::new(SERVER_BIN)
+                .args(["--port", "8117"])
+                .args([flag, "0"])
+                .stdout(Stdio::null())
+                .stderr(Stdio::null())
+                .status()
+                .expect("Failed to run server");
+
+            assert!(!status.success(), "Server should refuse {} 0", flag);
+        }
+    }
+
+    #[test]
+    fn dropped_client_resumes_session() {
+        let port = "8102";
+        let proxy_port = "8103";
+
+        // Start the server
+        let mut server = Command::new(SERVER_BIN)
+            .args(["--port", port])
+            .args(["--resume-grace", "10"])
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("Failed to start server");
+
+        assert!(wait_for_server(port), "Server failed to start");
+        let proxy = Proxy::start(proxy_port, port);
+
+        let (mut bob, bob_output) = connect_raw(port, "bob");
+        let mut alice = Command::new(CLIENT_BIN)
+            .args(["--username", "alice"])
+            .args(["--host", TEST_HOST])
+            .args(["--port", proxy_port])
+            .args(["--password", TEST_PASSWORD])
+            .arg("--register")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("Failed to start client");
+        let alice_output = spawn_output_reader(alice.stdout.take().expect("No client stdout"));
+        read_output_until(&bob_output, "alice|3|");
+
+        // Alice drops off the network while bob keeps talking
+        proxy.cut();
+        sleep(Duration::from_millis(500));
+        writeln!(bob, "bob|2|while you were away").expect("Failed to write");
+        sleep(Duration::from_millis(500));
+        proxy.resume();
+
+        let missed = read_output_until(&alice_output, "while you were away");
+        assert!(
+            missed.contains("bob : while you were away"),
+            "Alice should get the message she missed. Got: {}",
+            missed
+        );
+
+        // Bob never saw her leave, and she is back in the room
+        let mut alice_stdin = alice.stdin.take().expect("Failed to open stdin");
+        writeln!(alice_stdin, "back").expect("Failed to write to stdin");
+        alice_stdin.flush().expect("Failed to flush stdin");
+        let seen = read_output_until(&bob_output, "alice|2|back");
+        assert!(
+            seen.contains("alice|2|back") && !seen.contains("alice|4|"),
+            "Alice should resume without leaving. Got: {}",
+            seen
+        );
+
+        // Cleanup
+        alice.kill().expect("Failed to kill client");
+        alice.wait().expect("Failed to wait for client");
+        server.kill().expect("Failed to kill server");
+        server.wait().expect("Failed to wait for server");
+    }
+
+    #[test]
+    fn client_logs_in_again_after_server_restart() {
+        let port = "8104";
+        let accounts = std::env::temp_dir().join(format!("chat-accounts-{}", port));
+        let _ = fs::remove_file(&accounts);
+        let start_server = || {
+            Command::new(SERVER_BIN)
+                .args(["--port", port])
+                .args(["--accounts", &accounts.to_string_lossy()])
+                .stdout(Stdio::null())
+                .stderr(Stdio::null())
+                .spawn()
+                .expect("Failed to start server")
+        };
+
+        let mut server = start_server();
+        assert!(wait_for_server(port), "Server failed to start");
+
+        let mut alice = Command::new(CLIENT_BIN)
+            .args(["--username", "alice"])
+            .args(["--host", TEST_HOST])
+            .args(["--port", port])
+            .args(["--password", TEST_PASSWORD])
+            .arg("--register")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("Failed to start client");
+        sleep(Duration::from_millis(500));
+
+        // Crash and restart the server; its sessions are gone but accounts are not
+        server.kill().expect("Failed to kill server");
+        server.wait().expect("Failed to wait for server");
+        let mut server = start_server();
+        assert!(wait_for_server(port), "Server failed to restart");
+        let (_bob, bob_output) = connect_raw(port, "bob");
+        sleep(Duration::from_millis(500));
+
+        let mut alice_stdin = alice.stdin.take().expect("Failed to open stdin");
+        writeln!(alice_stdin, "back online").expect("Failed to write to stdin");
+        alice_stdin.flush().expect("Failed to flush stdin");
+        let received = read_output_until(&bob_output, "back online");
+        assert!(
+            received.contains("alice|2|back online"),
+            "Alice should reconnect and log in again. Got: {}",
+            received
+        );
+
+        // Cleanup
+        alice.kill().expect("Failed to kill client");
+        alice.wait().expect("Failed to wait for client");
+        server.kill().expect("Failed to kill server");
+        server.wait().expect("Failed to wait for server");
+        let _ = fs::remove_file(&accounts);
+    }
+
+    #[test]
+    fn slash_commands() {
+        let port = "8105";
+
+        // Start the server
+        let mut server = Command::new(SERVER_BIN)
+            .args(["--port", port])
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("Failed to start server");
+
+        assert!(wait_for_server(port), "Server failed to start");
+
+        let mut clients = Vec::new();
+        let mut outputs = Vec::new();
+        for username in ["alice", "bob"] {
+            let mut client = Command::new(CLIENT_BIN)
+                .args(["--username", username])
+                .args(["--host", TEST_HOST])
+                .args(["--port", port])
+                .args(["--password", TEST_PASSWORD])
+                .arg("--register")
+                .stdin(Stdio::piped())
+                .stdout(Stdio::piped())
+                .stderr(Stdio::null())
+                .spawn()
+                .unwrap_or_else(|_| panic!("Failed to start {}", username));
+            outputs.push(spawn_output_reader(
+                client.stdout.take().expect("No client stdout"),
+            ));
+            clients.push(client);
+            sleep(Duration::from_millis(300));
+        }
+
+        // Bob acts, then alice asks who is around and gets a usage error
+        let bob_stdin = clients[1].stdin.as_mut().expect("Failed to open stdin");
+        writeln!(bob_stdin, "/me waves").expect("Failed to write to stdin");
+        bob_stdin.flush().expect("Failed to flush stdin");
+
+        let alice_received = read_output_until(&outputs[0], "waves");
+        assert!(
+            alice_received.contains("* bob waves"),
+            "Alice should see bob's action. Got: {}",
+            alice_received
+        );
+
+        let alice_stdin = clients[0].stdin.as_mut().expect("Failed to open stdin");
+        writeln!(alice_stdin, "/who").expect("Failed to write to stdin");
+        alice_stdin.flush().expect("Failed to flush stdin");
+
+        let alice_received = read_output_until(&outputs[0], "alice, bob");
+        assert!(
+            alice_received.contains("In #general: alice, bob"),
+            "Alice should see who is in the room. Got: {}",
+            alice_received
+        );
+
+        writeln!(alice_stdin, "/msg bob").expect("Failed to write to stdin");
+        alice_stdin.flush().expect("Failed to flush stdin");
+
+        let alice_received = read_output_until(&outputs[0], "Usage");
+        assert!(
+            alice_received.contains("Usage: /msg <user> <message>"),
+            "Alice should see the usage of /msg. Got: {}",
+            alice_received
+        );
+
+        // Cleanup
+        for mut client in clients {
+            client.kill().expect("Failed to kill client");
+            client.wait().expect("Failed to wait for client");
+        }
+        server.kill().expect("Failed to kill server");
+        server.wait().expect("Failed to wait for server");
+    }
+
+    #[test]
+    fn roster_sent_after_login_and_on_request() {
+        let port = "8106";
+
+        // Start the server
+        let mut server = Command::new(SERVER_BIN)
+            .args(["--port", port])
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("Failed to start server");
+
+        assert!(wait_for_server(port), "Server failed to start");
+
+        let (mut alice, alice_output) = connect_raw(port, "alice");
+        read_output_until(&alice_output, "|28|alice");
+        let (_bob, bob_output) = connect_raw(port, "bob");
+
+        // Bob gets the roster right after logging in
+        let bob_received = read_output_until(&bob_output, "|28|");
+        assert!(
+            bob_received.contains("|28|alice,bob"),
+            "Bob should receive the roster after login. Got: {}",
+            bob_received
+        );
+
+        // Alice asks for it
+        writeln!(alice, "|28|").expect("Failed to write");
+        let alice_received = read_output_until(&alice_output, "|28|");
+        assert!(
+            alice_received.contains("|28|alice,bob"),
+            "Alice should receive the roster on request. Got: {}",
+            alice_received
+        );
+
+        // Cleanup
+        server.kill().expect("Failed to kill server");
+        server.wait().expect("Failed to wait for server");
+    }
+
+    #[test]
+    fn nickname_change() {
+        let port = "8107";
+
+        // Start the server
+        let mut server = Command::new(SERVER_BIN)
+            .args(["--port", port])
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("Failed to start server");
+
+        assert!(wait_for_server(port), "Server failed to start");
+
+        let mut clients = Vec::new();
+        let mut outputs = Vec::new();
+        for username in ["alice", "bob"] {
+            let mut client = Command::new(CLIENT_BIN)
+                .args(["--username", username])
+                .args(["--host", TEST_HOST])
+                .args(["--port", port])
+                .args(["--password", TEST_PASSWORD])
+                .arg("--register")
+                .stdin(Stdio::piped())
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped())
+                .spawn()
+                .unwrap_or_else(|_| panic!("Failed to start {}", username));
+            outputs.push(spawn_output_reader(
+                client.stdout.take().expect("No client stdout"),
+            ));
+            clients.push(client);
+            sleep(Duration::from_millis(300));
+        }
+        let bob_errors = spawn_output_reader(clients[1].stderr.take().expect("No stderr"));
+
+        // Alice renames herself and keeps talking under the new name
+        let alice_stdin = clients[0].stdin.as_mut().expect("Failed to open stdin");
+        writeln!(alice_stdin, "/nick alicia").expect("Failed to write to stdin");
+        alice_stdin.flush().expect("Failed to flush stdin");
+
+        let bob_received = read_output_until(&outputs[1], "known as");
+        assert!(
+            bob_received.contains("alice is now known as alicia"),
+            "Bob should be told about the rename. Got: {}",
+            bob_received
+        );
+
+        writeln!(alice_stdin, "hi again").expect("Failed to write to stdin");
+        alice_stdin.flush().expect("Failed to flush stdin");
+
+        let bob_received = read_output_until(&outputs[1], "hi again");
+        assert!(
+            bob_received.contains("alicia : hi again"),
+            "Alice's messages should carry her new name. Got: {}",
+            bob_received
+        );
+
+        // Bob cannot take her new name, nor her account's
+        let bob_stdin = clients[1].stdin.as_mut().expect("Failed to open stdin");
+        writeln!(bob_stdin, "/nick alicia").expect("Failed to write to stdin");
+        writeln!(bob_stdin, "/nick alice").expect("Failed to write to stdin");
+        bob_stdin.flush().expect("Failed to flush stdin");
+
+        let first = read_output_until(&bob_errors, "taken");
+        let second = read_output_until(&bob_errors, "taken");
+        assert!(
+            first.contains("That name is taken") && second.contains("That name is taken"),
+            "Bob should be refused both names. Got: {} / {}",
+            first,
+            second
+        );
+
+        // Cleanup
+        for mut client in clients {
+            client.kill().expect("Failed to kill client");
+            client.wait().expect("Failed to wait for client");
+        }
+        server.kill().expect("Failed to kill server");
+        server.wait().expect("Failed to wait for server");
+    }
+
+    #[test]
+    fn owner_login_takes_nickname_back() {
+        let port = "8123";
+
+        // Start the server
+        let mut server = Command::new(SERVER_BIN)
+            .args(["--port", port])
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("Failed to start server");
+
+        assert!(wait_for_server(port), "Server failed to start");
+
+        // Bob takes a name nobody has registered yet
+        let (mut bob, bob_output) = connect_raw(port, "bob");
+        read_output_until(&bob_output, "|28|");
+        writeln!(bob, "bob|29|zed").expect("Failed to write");
+        read_output_until(&bob_output, "bob|29|zed");
+
+        // Registering and logging in as zed renames bob back to his account
+        let (_zed, zed_output) = connect_raw(port, "zed");
+        let zed_received = read_output_until(&zed_output, "|28|");
+        assert!(
+            !zed_received.contains("|6|") && zed_received.contains("|28|"),
+            "Zed should log in under his own name. Got: {}",
+            zed_received
+        );
+        let bob_received = read_output_until(&bob_output, "zed|29|bob");
+        assert!(
+            bob_received.contains("zed|29|bob"),
+            "Bob should be renamed back. Got: {}",
+            bob_received
+        );
+
+        // Cleanup
+        server.kill().expect("Failed to kill server");
+        server.wait().expect("Failed to wait for server");
+    }
+
+    #[test]
+    fn message_sent_right_after_nick_delivered() {
+        let port = "8124";
+
+        // Start the server
+        let mut server = Command::new(SERVER_BIN)
+            .args(["--port", port])
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("Failed to start server");
+
+        assert!(wait_for_server(port), "Server failed to start");
+
+        let (mut alice, alice_output) = connect_raw(port, "alice");
+        read_output_until(&alice_output, "|28|");
+        let (_bob, bob_output) = connect_raw(port, "bob");
+        read_output_until(&bob_output, "|28|");
+
+        // Alice talks before the rename reaches her, so the frame still has her old name
+        write!(alice, "alice|29|alicia\nalice|2|hi right after nick\n").expect("Failed to write");
+
+        let bob_received = read_output_until(&bob_output, "right after nick");
+        assert!(
+            bob_received.contains("alicia|2|hi right after nick"),
+            "Bob should get the message under her new name. Got: {}",
+            bob_received
+        );
+        writeln!(alice, "|24|").expect("Failed to write");
+        let alice_received = read_output_until(&alice_output, "|25|");
+        assert!(
+            !alice_received.contains("|13|"),
+            "Alice should not be taken for an impersonator. Got: {}",
+            alice_received
+        );
+
+        // Cleanup
+        server.kill().expect("Failed to kill server");
+        server.wait().expect("Failed to wait for server");
+    }
+
+    #[test]
+    fn websocket_and_tcp_clients_share_a_room() {
+        let port = "8108";
+        let ws_port = "8109";
+
+        // Start the server with a WebSocket listener
+        let mut server = Command::new(SERVER_BIN)
+            .args(["--port", port])
+            .args(["--ws-port", ws_port])
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("Failed to start server");
+
+        assert!(wait_for_server(port), "Server failed to start");
+        assert!(
+            wait_for_server(ws_port),
+            "WebSocket listener failed to start"
+        );
+
+        let (mut alice, alice_output) = connect_raw(port, "alice");
+        read_output_until(&alice_output, "|28|alice");
+
+        // The browser user logs in and gets the roster like any other client
+        let mut bob = connect_websocket(ws_port, "bob");
+        let bob_received = read_frames_until(&mut bob, r#""ROSTER""#);
+        assert!(
+            bob_received.contains(r#"{"type":"ROSTER","data":["alice","bob"]}"#),
+            "Bob should receive the roster. Got: {}",
+            bob_received
+        );
+        let joined = read_output_until(&alice_output, "bob|3|");
+        assert!(
+            joined.contains("bob|3|"),
+            "Bob should join. Got: {}",
+            joined
+        );
+
+        // TCP to WebSocket
+        writeln!(alice, "|2|hi from tcp").expect("Failed to write");
+        let bob_received = read_frames_until(&mut bob, "hi from tcp");
+        assert!(
+            bob_received.contains(r#"{"type":"MSG","data":["alice","hi from tcp"]}"#),
+            "Bob should receive alice's message as a text frame. Got: {}",
+            bob_received
+        );
+
+        // WebSocket to TCP
+        bob.send(WsMessage::text(
+            r#"{"type":"MSG","data":["bob","hi from the browser"]}"#,
+        ))
+        .expect("Failed to send");
+        let alice_received = read_output_until(&alice_output, "hi from the browser");
+        assert!(
+            alice_received.contains("bob|2|hi from the browser"),
+            "Alice should receive bob's message as a pipe frame. Got: {}",
+            alice_received
+        );
+
+        // Closing the WebSocket leaves the room
+        bob.close(None).expect("Failed to close");
+        let left = read_output_until(&alice_output, "bob|4|");
+        assert!(left.contains("bob|4|"), "Bob should leave. Got: {}", left);
+
+        // Cleanup
+        server.kill().expect("Failed to kill server");
+        server.wait().expect("Failed to wait for server");
+    }
+
+    #[test]
+    fn admin_api_describes_users_rooms_and_connections() {
+        let port = "8110";
+        let admin_port = "8111";
+
+        // Start the server with the admin API
+        let mut server = Command::new(SERVER_BIN)
+            .args(["--port", port])
+            .args(["--admin-port", admin_port])
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("Failed to start server");
+
+        assert!(wait_for_server(port), "Server failed to start");
+        assert!(wait_for_server(admin_port), "Admin API failed to start");
+
+        let (mut alice, alice_output) = connect_raw(port, "alice");
+        read_output_until(&alice_output, "|28|");
+        let (_bob, bob_output) = connect_raw(port, "bob");
+        read_output_until(&bob_output, "|28|");
+        writeln!(alice, "alice|8|project").expect("Failed to write");
+        read_output_until(&alice_output, "|28|");
+
+        let users = http_get(admin_port, "/users");
+        assert_eq!(users, r#"["alice","bob"]"#);
+
+        let rooms = http_get(admin_port, "/rooms");
+        assert_eq!(
+            rooms,
+            r#"[{"name":"general","members":["bob"]},{"name":"project","members":["alice"]}]"#
+        );
+
+        let status = http_get(admin_port, "/status");
+        assert!(
+            status.contains(r#""users":2,"rooms":2,"connections":2"#),
+            "Status should count users, rooms and connections. Got: {}",
+            status
+        );
+
+        let connections = http_get(admin_port, "/connections");
+        assert!(
+            connections.contains(r#""username":"alice","room":"project""#),
+            "Alice's connection should be listed. Got: {}",
+            connections
+        );
+        assert!(
+            connections.contains(r#""username":"bob","room":"general""#),
+            "Bob's connection should be listed. Got: {}",
+            connections
+        );
+        assert!(
+            !connections.contains(r#""bytes_in":0"#)
+                && !connections.contains(r#""messages_out":0"#),
+            "Traffic should be counted. Got: {}",
+            connections
+        );
+
+        // Cleanup
+        server.kill().expect("Failed to kill server");
+        server.wait().expect("Failed to wait for server");
+    }
+
+    #[test]
+    fn metrics_endpoint_counts_load() {
+        let port = "8112";
+        let admin_port = "8113";
+
+        // Start the server with the admin API
+        let mut server = Command::new(SERVER_BIN)
+            .args(["--port", port])
+            .args(["--admin-port", admin_port])
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("Failed to start server");
+
+        assert!(wait_for_server(port), "Server failed to start");
+        assert!(wait_for_server(admin_port), "Admin API failed to start");
+
+        let (mut alice, alice_output) = connect_raw(port, "alice");
+        read_output_until(&alice_output, "|28|");
+        let (_bob, bob_output) = connect_raw(port, "bob");
+        read_output_until(&bob_output, "|28|");
+        writeln!(alice, "|2|hello").expect("Failed to write");
+        read_output_until(&bob_output, "alice|2|hello");
+
+        // A failed login
+        let mut mallory =
+            TcpStream::connect(format!("{}:{}", TEST_HOST, port)).expect("Failed to connect");
+        let mallory_output =
+            spawn_output_reader(mallory.try_clone().expect("Failed to clone stream"));
+        writeln!(mallory, "alice|1|wrong").expect("Failed to write");
+        read_output_until(&mallory_output, "|");
+        sleep(Duration::from_millis(200));
+
+        // The readiness check in wait_for_server is a connection that never logs in
+        let metrics = http_get(admin_port, "/metrics");
+        for expected in [
+            "chat_connections_accepted_total 4",
+            "chat_auth_failures_total 2",
+            "chat_active_connections 2",
+            "chat_broadcast_fanout_count",
+            "chat_messages_received_total",
+            "chat_messages_sent_total",
+            "chat_queued_messages 0",
+            "chat_dropped_messages_total 0",
+        ] {
+            assert!(
+                metrics.contains(expected),
+                "Metrics should contain {:?}. Got: {}",
+                expected,
+                metrics
+            );
+        }
+
+        // Cleanup
+        server.kill().expect("Failed to kill server");
+        server.wait().expect("Failed to wait for server");
+    }
+
+    #[test]
+    fn flooding_client_rate_limited_then_disconnected() {
+        let port = "8114";
+
+        // Start the server with a low per-user limit
+        let mut server = Command::new(SERVER_BIN)
+            .args(["--port", port])
+            .args(["--user-message-rate", "2"])
+            .args(["--rate-limit-strikes", "3"])
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("Failed to start server");
+
+        assert!(wait_for_server(port), "Server failed to start");
+
+        let (mut alice, alice_output) = connect_raw(port, "alice");
+        read_output_until(&alice_output, "|28|");
+        let (_bob, bob_output) = connect_raw(port, "bob");
+        read_output_until(&bob_output, "|28|");
+        read_output_until(&alice_output, "bob|3|");
+
+        // Alice floods the room
+        for i in 1..=10 {
+            writeln!(alice, "|2|flood {}", i).expect("Failed to write");
+        }
+
+        let alice_received = read_output_until(&alice_output, "never sent");
+        assert_eq!(
+            alice_received.matches("|30|").count(),
+            3,
+            "Alice should be told she is rate limited until disconnected. Got: {}",
+            alice_received
+        );
+
+        let bob_received = read_output_until(&bob_output, "alice|4|");
+        assert!(
+            bob_received.contains("alice|2|flood 2") && !bob_received.contains("flood 3"),
+            "Bob should only get the messages within the limit. Got: {}",
+            bob_received
+        );
+        assert!(
+            bob_received.contains("alice|4|"),
+            "Alice should be disconnected. Got: {}",
+            bob_received
+        );
+
+        // Cleanup
+        server.kill().expect("Failed to kill server");
+        server.wait().expect("Failed to wait for server");
+    }
+
+    #[test]
+    fn oversized_frames_answered_with_too_large() {
+        let port = "8115";
+
+        // Start the server with small limits, and no byte rates the junk would exhaust
+        let mut server = Command::new(SERVER_BIN)
+            .args(["--port", port])
+            .args(["--max-line-length", "64"])
+            .args(["--max-text-length", "16"])
+            .args(["--user-byte-rate", "0"])
+            .args(["--ip-byte-rate", "0"])
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("Failed to start server");
+
+        assert!(wait_for_server(port), "Server failed to start");
+
+        let (mut alice, alice_output) = connect_raw(port, "alice");
+        read_output_until(&alice_output, "|28|");
+        let (_bob, bob_output) = connect_raw(port, "bob");
+        read_output_until(&bob_output, "|28|");
+
+        // A line far beyond the limit, written in pieces
+        write!(alice, "|2|").expect("Failed to write");
+        for _ in 0..100 {
+            alice.write_all(&[b'x'; 1024]).expect("Failed to write");
+        }
+        writeln!(alice).expect("Failed to write");
+        let alice_received = read_output_until(&alice_output, "|31|");
+        assert!(
+            alice_received.contains("|31|"),
+            "Alice should be told the line was too large. Got: {}",
+            alice_received
+        );
+
+        // A short line carrying too much text
+        writeln!(alice, "|2|{}", "y".repeat(17)).expect("Failed to write");
+        let alice_received = read_output_until(&alice_output, "|31|");
+        assert!(
+            alice_received.contains("|31|"),
+            "Alice should be told the text was too large. Got: {}",
+            alice_received
+        );
+
+        // The connection is still usable and nothing oversized was delivered
+        writeln!(alice, "|2|still here").expect("Failed to write");
+        let bob_received = read_output_until(&bob_output, "still here");
+        assert!(
+            bob_received.contains("alice|2|still here")
+                && !bob_received.contains("xxx")
+                && !bob_received.contains("yyy"),
+            "Bob should only receive the short message. Got: {}",
+            bob_received
+        );
+
+        // Cleanup
+        server.kill().expect("Failed to kill server");
+        server.wait().expect("Failed to wait for server");
+    }
+
+    #[test]
+    fn skipped_bytes_count_against_byte_rate() {
+        let port = "8122";
+
+        // Start the server with a low byte rate and a small line limit
+        let mut server = Command::new(SERVER_BIN)
+            .args(["--port", port])
+            .args(["--max-line-length", "64"])
+            .args(["--user-byte-rate", "1024"])
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("Failed to start server");
+
+        assert!(wait_for_server(port), "Server failed to start");
+
+        let (mut alice, alice_output) = connect_raw(port, "alice");
+        read_output_until(&alice_output, "|28|");
+
+        // An overlong line is skipped, but its bytes were still received
+        write!(alice, "|2|").expect("Failed to write");
+        alice.write_all(&[b'x'; 8 * 1024]).expect("Failed to write");
+        writeln!(alice).expect("Failed to write");
+        writeln!(alice, "|2|first").expect("Failed to write");
+        writeln!(alice, "|2|second").expect("Failed to write");
+        let alice_received = read_output_until(&alice_output, "|30|");
+        assert!(
+            alice_received.contains("|30|"),
+            "Alice should be rate limited after the overlong line. Got: {}",
+            alice_received
+        );
+
+        // Cleanup
+        server.kill().expect("Failed to kill server");
+        server.wait().expect("Failed to wait for server");
+    }
+
+    #[test]
+    fn client_refuses_overlong_message() {
+        let port = "8116";
+
+        // Start the server
+        let mut server = Command::new(SERVER_BIN)
+            .args(["--port", port])
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("Failed to start server");
+
+        assert!(wait_for_server(port), "Server failed to start");
+
+        let (_bob, bob_output) = connect_raw(port, "bob");
+        read_output_until(&bob_output, "|28|");
+
+        let mut client = Command::new(CLIENT_BIN)
+            .args(["--username", "alice"])
+            .args(["--host", TEST_HOST])
+            .args(["--port", port])
+            .args(["--password", TEST_PASSWORD])
+            .arg("--register")
+            .args(["--max-text-length", "10"])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .spawn()
+            .expect("Failed to start client");
+        let errors = spawn_output_reader(client.stderr.take().expect("No client stderr"));
+        read_output_until(&bob_output, "alice|3|");
+
+        let stdin = client.stdin.as_mut().expect("Failed to open stdin");
+        writeln!(stdin, "this is longer than ten bytes").expect("Failed to write to stdin");
+        writeln!(stdin, "short").expect("Failed to write to stdin");
+        stdin.flush().expect("Failed to flush stdin");
+
+        let client_errors = read_output_until(&errors, "too long");
+        assert!(
+            client_errors.contains("Message too long; it was not delivered"),
+            "The client should refuse the long message. Got: {}",
+            client_errors
+        );
+        let bob_received = read_output_until(&bob_output, "alice|2|short");
+        assert!(
+            bob_received.contains("alice|2|short") && !bob_received.contains("longer"),
+            "Bob should only receive the short message. Got: {}",
+            bob_received
+        );
+
+        // Cleanup
+        client.kill().expect("Failed to kill client");
+        client.wait().expect("Failed to wait for client");
+        server.kill().expect("Failed to kill server");
+        server.wait().expect("Failed to wait for server");
+    }
 }
@@ -0,0 +1,50 @@
+//! Broadcast fan-out throughput: one message queued for every member of a room and
+//! encoded by each recipient's writer.
+use criterion::{BenchmarkId, Criterion, Throughput, criterion_group, criterion_main};
+use server::{
+    queue::{ClientReceiver, SlowConsumerPolicy, channel},
+    room::Room,
+};
+use std::hint::black_box;
+use utils::{codec::WireFormat, message::Message};
+
+fn room_with(recipients: usize) -> (Room, Vec<ClientReceiver>) {
+    let room = Room::new();
+    let receivers = (0..recipients)
+        .map(|i| {
+            let (tx, rx) = channel(16, SlowConsumerPolicy::DropOldest);
+            room.add_user(format!("user{}", i), tx).unwrap();
+            rx
+        })
+        .collect();
+    (room, receivers)
+}
+
+fn broadcast(c: &mut Criterion) {
+    let sender = "sender".to_string();
+    let text = "x".repeat(128);
+
+    let mut group = c.benchmark_group("broadcast");
+    for recipients in [10, 100, 1000] {
+        let (room, mut receivers) = room_with(recipients);
+        group.throughput(Throughput::Elements(recipients as u64));
+        group.bench_with_input(
+            BenchmarkId::from_parameter(recipients),
+            &recipients,
+            |b, _| {
+                b.iter(|| {
+                    let message = Message::MSG(sender.clone(), text.clone());
+                    room.broadcast_message(message, &sender);
+                    for receiver in &mut receivers {
+                        let frame = receiver.try_recv().unwrap();
+                        black_box(frame.encoded(WireFormat::Pipe));
+                    }
+                })
+            },
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, broadcast);
+criterion_main!(benches);
@@ -0,0 +1,77 @@
+use crate::{connection::ConnectionInfo, server::ServerChat};
+use axum::{
+    Json, Router, extract::State, http::header::CONTENT_TYPE, response::IntoResponse, routing::get,
+};
+use serde::Serialize;
+use std::{io, sync::Arc};
+use tokio::net::TcpListener;
+
+/// Overview returned by `GET /status`.
+#[derive(Debug, Serialize)]
+pub struct Status {
+    pub uptime_secs: u64,
+    pub users: usize,
+    pub rooms: usize,
+    pub connections: usize,
+}
+
+/// A room and its members, as returned by `GET /rooms`.
+#[derive(Debug, Serialize)]
+pub struct RoomInfo {
+    pub name: String,
+    pub members: Vec<String>,
+}
+
+/// Read-only JSON endpoints describing `server`:
+///
+/// - `GET /status`: uptime and how many users, rooms and connections there are
+/// - `GET /users`: names of the logged-in users
+/// - `GET /rooms`: every room with its members
+/// - `GET /connections`: each open connection with its user and traffic counters
+///
+/// plus `GET /metrics` in the Prometheus text format.
+pub fn router(server: Arc<ServerChat>) -> Router {
+    Router::new()
+        .route("/status", get(status))
+        .route("/users", get(users))
+        .route("/rooms", get(rooms))
+        .route("/connections", get(connections))
+        .route("/metrics", get(metrics))
+        .with_state(server)
+}
+
+/// Serves the admin API on `listener` until it fails.
+pub async fn serve(listener: TcpListener, server: Arc<ServerChat>) -> io::Result<()> {
+    axum::serve(listener, router(server)).await
+}
+
+async fn status(State(server): State<Arc<ServerChat>>) -> Json<Status> {
+    Json(Status {
+        uptime_secs: server.uptime().as_secs(),
+        users: server.users().len(),
+        rooms: server.rooms().await.len(),
+        connections: server.connections().len(),
+    })
+}
+
+async fn users(State(server): State<Arc<ServerChat>>) -> Json<Vec<String>> {
+    Json(server.users())
+}
+
+async fn rooms(State(server): State<Arc<ServerChat>>) -> Json<Vec<RoomInfo>> {
+    let rooms = server
+        .rooms()
+        .await
+        .into_iter()
+        .map(|(name, members)| RoomInfo { name, members })
+        .collect();
+    Json(rooms)
+}
+
+async fn connections(State(server): State<Arc<ServerChat>>) -> Json<Vec<ConnectionInfo>> {
+    Json(server.connections())
+}
+
+async fn metrics(State(server): State<Arc<ServerChat>>) -> impl IntoResponse {
+    ([(CONTENT_TYPE, prometheus::TEXT_FORMAT)], server.metrics())
+}
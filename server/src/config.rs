@@ -0,0 +1,47 @@
+use crate::{queue::SlowConsumerPolicy, ratelimit::RateLimitConfig};
+use std::time::Duration;
+use utils::protocol::{DEFAULT_MAX_LINE_LENGTH, DEFAULT_MAX_TEXT_LENGTH, LEGACY_PROTOCOL_VERSION};
+
+/// Tunables for a [`ServerChat`](crate::server::ServerChat).
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    /// Oldest protocol version accepted from clients.
+    pub min_protocol_version: u16,
+    /// Number of past messages replayed to a user entering a room; also the page size
+    /// for `HISTORY` requests.
+    pub history_replay: usize,
+    /// Messages queued for a client before the slow-consumer policy applies.
+    pub client_queue_capacity: usize,
+    /// Applied to a client whose queue is full.
+    pub slow_consumer_policy: SlowConsumerPolicy,
+    /// How often an authenticated client is sent a `PING`. Must not be zero.
+    pub heartbeat_interval: Duration,
+    /// How long a client may stay silent, `PONG`s included, before it is dropped. Must not
+    /// be zero.
+    pub idle_timeout: Duration,
+    /// How long a dropped session is held for the client to resume it.
+    pub resume_grace: Duration,
+    /// Limits on how fast logged-in clients may send.
+    pub rate_limit: RateLimitConfig,
+    /// Longest line or WebSocket message, in bytes, read from a client.
+    pub max_line_length: usize,
+    /// Longest text, in bytes, of a message to a room or a user.
+    pub max_text_length: usize,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            min_protocol_version: LEGACY_PROTOCOL_VERSION,
+            history_replay: 20,
+            client_queue_capacity: 1024,
+            slow_consumer_policy: SlowConsumerPolicy::default(),
+            heartbeat_interval: Duration::from_secs(30),
+            idle_timeout: Duration::from_secs(90),
+            resume_grace: Duration::from_secs(30),
+            rate_limit: RateLimitConfig::default(),
+            max_line_length: DEFAULT_MAX_LINE_LENGTH,
+            max_text_length: DEFAULT_MAX_TEXT_LENGTH,
+        }
+    }
+}
@@ -0,0 +1,310 @@
+use crate::queue::QueueStats;
+use dashmap::DashMap;
+use serde::Serialize;
+use std::{
+    io,
+    net::SocketAddr,
+    ops::Deref,
+    pin::Pin,
+    sync::{
+        Arc, Mutex, OnceLock,
+        atomic::{AtomicU64, Ordering},
+    },
+    task::{Context, Poll},
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    time::Instant,
+};
+
+/// One client connection and the traffic it has seen so far.
+#[derive(Debug)]
+pub struct Connection {
+    id: u64,
+    peer: SocketAddr,
+    opened: Instant,
+    /// Username and room, once logged in.
+    seat: Mutex<Option<(String, String)>>,
+    /// The client's outgoing queue, once it has one.
+    queue: OnceLock<QueueStats>,
+    /// Capabilities agreed on in the handshake, if the client made one.
+    capabilities: OnceLock<Vec<String>>,
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+    messages_in: AtomicU64,
+    messages_out: AtomicU64,
+}
+
+/// What the admin API reports about a [`Connection`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ConnectionInfo {
+    pub id: u64,
+    pub peer: SocketAddr,
+    pub username: Option<String>,
+    pub room: Option<String>,
+    pub connected_secs: u64,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    pub messages_in: u64,
+    pub messages_out: u64,
+    /// Messages discarded because the client fell behind.
+    pub dropped: u64,
+    /// Whether new messages are being discarded until the client catches up.
+    pub lagging: bool,
+}
+
+impl Connection {
+    fn new(id: u64, peer: SocketAddr) -> Self {
+        Connection {
+            id,
+            peer,
+            opened: Instant::now(),
+            seat: Mutex::new(None),
+            queue: OnceLock::new(),
+            capabilities: OnceLock::new(),
+            bytes_in: AtomicU64::new(0),
+            bytes_out: AtomicU64::new(0),
+            messages_in: AtomicU64::new(0),
+            messages_out: AtomicU64::new(0),
+        }
+    }
+
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Address the connection came from.
+    pub fn peer(&self) -> SocketAddr {
+        self.peer
+    }
+
+    /// Records who is using the connection and where, after login and every change.
+    pub fn seat(&self, username: &str, room: &str) {
+        *self.seat.lock().unwrap() = Some((username.to_string(), room.to_string()));
+    }
+
+    /// Reports on `queue` as this connection's outgoing queue.
+    pub fn watch_queue(&self, queue: QueueStats) {
+        let _ = self.queue.set(queue);
+    }
+
+    /// Records the capabilities agreed on with the client.
+    pub fn negotiated(&self, capabilities: Vec<String>) {
+        let _ = self.capabilities.set(capabilities);
+    }
+
+    /// Whether the client agreed on `capability`. Clients that skip the handshake agree
+    /// on none.
+    pub fn supports(&self, capability: &str) -> bool {
+        self.capabilities
+            .get()
+            .is_some_and(|capabilities| capabilities.iter().any(|c| c == capability))
+    }
+
+    pub fn received_message(&self) {
+        self.messages_in.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn sent_message(&self) {
+        self.messages_out.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn info(&self) -> ConnectionInfo {
+        let seat = self.seat.lock().unwrap().clone();
+        let (username, room) = seat.unzip();
+        let queue = self.queue.get();
+        ConnectionInfo {
+            id: self.id,
+            peer: self.peer,
+            username,
+            room,
+            connected_secs: self.opened.elapsed().as_secs(),
+            bytes_in: self.bytes_in.load(Ordering::Relaxed),
+            bytes_out: self.bytes_out.load(Ordering::Relaxed),
+            messages_in: self.messages_in.load(Ordering::Relaxed),
+            messages_out: self.messages_out.load(Ordering::Relaxed),
+            dropped: queue.map_or(0, QueueStats::dropped),
+            lagging: queue.is_some_and(QueueStats::is_lagging),
+        }
+    }
+}
+
+/// The open connections of a server.
+#[derive(Debug, Default)]
+pub struct Connections {
+    next_id: AtomicU64,
+    open: Arc<DashMap<u64, Arc<Connection>>>,
+}
+
+impl Connections {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers a new connection from `peer`, listed until the returned handle is dropped.
+    pub fn open(&self, peer: SocketAddr) -> Registration {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+        let connection = Arc::new(Connection::new(id, peer));
+        self.open.insert(id, Arc::clone(&connection));
+        Registration {
+            connection,
+            open: Arc::clone(&self.open),
+        }
+    }
+
+    /// Describes the open connections, oldest first.
+    pub fn list(&self) -> Vec<ConnectionInfo> {
+        let mut connections: Vec<ConnectionInfo> =
+            self.open.iter().map(|entry| entry.value().info()).collect();
+        connections.sort_by_key(|connection| connection.id);
+        connections
+    }
+
+    pub fn len(&self) -> usize {
+        self.open.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.open.is_empty()
+    }
+}
+
+/// Keeps a [`Connection`] listed in [`Connections`] while it is alive.
+#[derive(Debug)]
+pub struct Registration {
+    connection: Arc<Connection>,
+    open: Arc<DashMap<u64, Arc<Connection>>>,
+}
+
+impl Registration {
+    /// The connection, shared with tasks that update its counters.
+    pub fn connection(&self) -> Arc<Connection> {
+        Arc::clone(&self.connection)
+    }
+
+    /// Wraps `stream` so the bytes it carries are counted on this connection.
+    pub fn meter<S>(&self, stream: S) -> Metered<S> {
+        Metered {
+            inner: stream,
+            connection: self.connection(),
+        }
+    }
+}
+
+impl Deref for Registration {
+    type Target = Connection;
+
+    fn deref(&self) -> &Connection {
+        &self.connection
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        self.open.remove(&self.connection.id);
+    }
+}
+
+/// A byte stream counting what is read from and written to it.
+pub struct Metered<S> {
+    inner: S,
+    connection: Arc<Connection>,
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Metered<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        let result = Pin::new(&mut self.inner).poll_read(cx, buf);
+        let read = (buf.filled().len() - before) as u64;
+        self.connection.bytes_in.fetch_add(read, Ordering::Relaxed);
+        result
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Metered<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let result = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(written)) = result {
+            self.connection
+                .bytes_out
+                .fetch_add(written as u64, Ordering::Relaxed);
+        }
+        result
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::Connections;
+    use crate::queue::{SlowConsumerPolicy, channel};
+    use std::net::SocketAddr;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt, duplex};
+    use utils::message::Message;
+
+    const PEER: SocketAddr =
+        SocketAddr::new(std::net::IpAddr::V4(std::net::Ipv4Addr::LOCALHOST), 40000);
+
+    #[tokio::test]
+    async fn listed_until_dropped() {
+        let connections = Connections::new();
+        let first = connections.open(PEER);
+        let second = connections.open(PEER);
+        second.seat("alice", "general");
+        assert_eq!(connections.len(), 2);
+
+        drop(first);
+        let listed = connections.list();
+        assert_eq!(listed.len(), 1);
+        assert_eq!(listed[0].id, second.id());
+        assert_eq!(listed[0].peer, PEER);
+        assert_eq!(listed[0].username.as_deref(), Some("alice"));
+        assert_eq!(listed[0].room.as_deref(), Some("general"));
+    }
+
+    #[tokio::test]
+    async fn metered_stream_counts_bytes() {
+        let connections = Connections::new();
+        let connection = connections.open(PEER);
+        let (local, mut remote) = duplex(64);
+        let mut local = connection.meter(local);
+
+        local.write_all(b"hello\n").await.unwrap();
+        remote.write_all(b"hi\n").await.unwrap();
+        let mut buf = [0; 3];
+        local.read_exact(&mut buf).await.unwrap();
+
+        let info = connection.info();
+        assert_eq!((info.bytes_in, info.bytes_out), (3, 6));
+    }
+
+    #[tokio::test]
+    async fn reports_queue() {
+        let connections = Connections::new();
+        let connection = connections.open(PEER);
+        let (sender, _receiver) = channel(1, SlowConsumerPolicy::MarkLagging);
+        connection.watch_queue(sender.stats());
+        for name in ["a", "b", "c"] {
+            sender.send(Message::JOIN(name.to_string())).unwrap();
+        }
+
+        let info = connection.info();
+        assert_eq!((info.dropped, info.lagging), (2, true));
+    }
+}
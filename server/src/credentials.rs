@@ -0,0 +1,242 @@
+use anyhow::Result;
+use argon2::Argon2;
+use password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString, rand_core::OsRng};
+use std::{
+    collections::HashMap,
+    fmt,
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    path::PathBuf,
+    sync::Mutex,
+};
+
+/// Separates the username from the password hash on each line of an accounts file.
+const FIELD_SEPARATOR: char = ':';
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum CredentialError {
+    NoAccount,
+    BadPassword,
+    AccountExists,
+    InvalidUsername,
+    EmptyPassword,
+    Storage(String),
+}
+
+impl fmt::Display for CredentialError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CredentialError::NoAccount => write!(f, "No such account"),
+            CredentialError::BadPassword => write!(f, "Wrong password"),
+            CredentialError::AccountExists => write!(f, "Account already exists"),
+            CredentialError::InvalidUsername => write!(f, "Invalid username"),
+            CredentialError::EmptyPassword => write!(f, "Password must not be empty"),
+            CredentialError::Storage(e) => write!(f, "Credential storage failed: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for CredentialError {}
+
+/// Persists accounts as salted password hashes.
+///
+/// Methods are blocking (hashing is deliberately slow), so async callers should run them
+/// on a blocking thread.
+pub trait CredentialStore: Send + Sync {
+    /// Creates an account, failing if the username is already registered.
+    fn register(&self, username: &str, password: &str) -> Result<(), CredentialError>;
+
+    /// Checks `password` against the stored hash for `username`.
+    fn verify(&self, username: &str, password: &str) -> Result<(), CredentialError>;
+
+    /// Whether `username` is registered. Cheap, unlike the other methods.
+    fn exists(&self, username: &str) -> bool;
+}
+
+/// Accounts kept in memory only; they are lost when the server stops.
+#[derive(Default)]
+pub struct InMemoryCredentialStore {
+    accounts: Mutex<HashMap<String, String>>,
+}
+
+impl InMemoryCredentialStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl CredentialStore for InMemoryCredentialStore {
+    fn register(&self, username: &str, password: &str) -> Result<(), CredentialError> {
+        let hash = new_account_hash(username, password)?;
+        let mut accounts = self.accounts.lock().unwrap();
+        if accounts.contains_key(username) {
+            return Err(CredentialError::AccountExists);
+        }
+        accounts.insert(username.to_string(), hash);
+        Ok(())
+    }
+
+    fn verify(&self, username: &str, password: &str) -> Result<(), CredentialError> {
+        let hash = self.accounts.lock().unwrap().get(username).cloned();
+        verify_password(hash.as_deref(), password)
+    }
+
+    fn exists(&self, username: &str) -> bool {
+        self.accounts.lock().unwrap().contains_key(username)
+    }
+}
+
+/// Accounts stored one `username:hash` per line in a local file.
+pub struct FileCredentialStore {
+    path: PathBuf,
+    accounts: Mutex<HashMap<String, String>>,
+}
+
+impl FileCredentialStore {
+    /// Loads the accounts file at `path`, creating it if it does not exist yet.
+    pub fn open(path: impl Into<PathBuf>) -> Result<Self> {
+        let path = path.into();
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .read(true)
+            .open(&path)?;
+
+        let mut accounts = HashMap::new();
+        for line in BufReader::new(file).lines() {
+            let line = line?;
+            if let Some((username, hash)) = line.split_once(FIELD_SEPARATOR) {
+                accounts.insert(username.to_string(), hash.to_string());
+            }
+        }
+
+        Ok(Self {
+            path,
+            accounts: Mutex::new(accounts),
+        })
+    }
+
+    fn append(&self, username: &str, hash: &str) -> std::io::Result<()> {
+        let mut file: File = OpenOptions::new().append(true).open(&self.path)?;
+        writeln!(file, "{}{}{}", username, FIELD_SEPARATOR, hash)
+    }
+}
+
+impl CredentialStore for FileCredentialStore {
+    fn register(&self, username: &str, password: &str) -> Result<(), CredentialError> {
+        let hash = new_account_hash(username, password)?;
+        let mut accounts = self.accounts.lock().unwrap();
+        if accounts.contains_key(username) {
+            return Err(CredentialError::AccountExists);
+        }
+        self.append(username, &hash)
+            .map_err(|e| CredentialError::Storage(e.to_string()))?;
+        accounts.insert(username.to_string(), hash);
+        Ok(())
+    }
+
+    fn verify(&self, username: &str, password: &str) -> Result<(), CredentialError> {
+        let hash = self.accounts.lock().unwrap().get(username).cloned();
+        verify_password(hash.as_deref(), password)
+    }
+
+    fn exists(&self, username: &str) -> bool {
+        self.accounts.lock().unwrap().contains_key(username)
+    }
+}
+
+/// Usernames travel inside pipe-separated frames and accounts files, so keep them plain.
+pub fn is_valid_username(username: &str) -> bool {
+    !username.is_empty()
+        && username
+            .chars()
+            .all(|c| c.is_alphanumeric() || c == '-' || c == '_' || c == '.')
+}
+
+fn new_account_hash(username: &str, password: &str) -> Result<String, CredentialError> {
+    if !is_valid_username(username) {
+        return Err(CredentialError::InvalidUsername);
+    }
+    if password.is_empty() {
+        return Err(CredentialError::EmptyPassword);
+    }
+
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|e| CredentialError::Storage(e.to_string()))
+}
+
+fn verify_password(hash: Option<&str>, password: &str) -> Result<(), CredentialError> {
+    let hash = hash.ok_or(CredentialError::NoAccount)?;
+    let parsed = PasswordHash::new(hash).map_err(|e| CredentialError::Storage(e.to_string()))?;
+    Argon2::default()
+        .verify_password(password.as_bytes(), &parsed)
+        .map_err(|_| CredentialError::BadPassword)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::{CredentialError, CredentialStore, FileCredentialStore, InMemoryCredentialStore};
+
+    #[test]
+    fn register_and_verify() {
+        let store = InMemoryCredentialStore::new();
+        store.register("alice", "hunter2").unwrap();
+
+        assert_eq!(store.verify("alice", "hunter2"), Ok(()));
+        assert_eq!(
+            store.verify("alice", "hunter3"),
+            Err(CredentialError::BadPassword)
+        );
+        assert_eq!(
+            store.verify("bob", "hunter2"),
+            Err(CredentialError::NoAccount)
+        );
+    }
+
+    #[test]
+    fn register_twice_rejected() {
+        let store = InMemoryCredentialStore::new();
+        store.register("alice", "hunter2").unwrap();
+        assert!(store.exists("alice"));
+        assert!(!store.exists("bob"));
+
+        assert_eq!(
+            store.register("alice", "other"),
+            Err(CredentialError::AccountExists)
+        );
+    }
+
+    #[test]
+    fn invalid_registration_rejected() {
+        let store = InMemoryCredentialStore::new();
+
+        assert_eq!(
+            store.register("a|b", "hunter2"),
+            Err(CredentialError::InvalidUsername)
+        );
+        assert_eq!(
+            store.register("alice", ""),
+            Err(CredentialError::EmptyPassword)
+        );
+    }
+
+    #[test]
+    fn file_store_persists_accounts() {
+        let path = std::env::temp_dir().join(format!("chat-accounts-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        FileCredentialStore::open(&path)
+            .unwrap()
+            .register("alice", "hunter2")
+            .unwrap();
+        let reopened = FileCredentialStore::open(&path).unwrap();
+
+        assert_eq!(reopened.verify("alice", "hunter2"), Ok(()));
+        assert!(!std::fs::read_to_string(&path).unwrap().contains("hunter2"));
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,280 @@
+use anyhow::{Result, bail};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, VecDeque},
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, BufWriter, Write},
+    ops::Range,
+    path::PathBuf,
+    sync::{Mutex, mpsc},
+    thread::{self, JoinHandle},
+};
+use utils::message::Message;
+
+/// Append-only log of the messages sent in each room.
+pub trait HistoryStore: Send + Sync {
+    fn append(&self, room: &str, message: &Message) -> Result<()>;
+
+    /// Returns page `page` (starting at 1 for the newest) of `room`'s history, with
+    /// `page_size` messages per page, oldest first.
+    fn page(&self, room: &str, page: usize, page_size: usize) -> Result<Vec<Message>>;
+}
+
+/// Messages kept per room unless configured otherwise.
+pub const DEFAULT_HISTORY_LIMIT: usize = 1000;
+
+/// History kept in memory only; it is lost when the server stops. Only the newest
+/// messages of each room are kept.
+pub struct InMemoryHistoryStore {
+    rooms: Mutex<HashMap<String, VecDeque<Message>>>,
+    limit: usize,
+}
+
+impl Default for InMemoryHistoryStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl InMemoryHistoryStore {
+    pub fn new() -> Self {
+        Self::with_limit(DEFAULT_HISTORY_LIMIT)
+    }
+
+    /// Keeps the newest `limit` messages of each room.
+    pub fn with_limit(limit: usize) -> Self {
+        InMemoryHistoryStore {
+            rooms: Mutex::new(HashMap::new()),
+            limit,
+        }
+    }
+}
+
+impl HistoryStore for InMemoryHistoryStore {
+    fn append(&self, room: &str, message: &Message) -> Result<()> {
+        let mut rooms = self.rooms.lock().unwrap();
+        let messages = rooms.entry(room.to_string()).or_default();
+        messages.push_back(message.clone());
+        if messages.len() > self.limit {
+            messages.pop_front();
+        }
+        Ok(())
+    }
+
+    fn page(&self, room: &str, page: usize, page_size: usize) -> Result<Vec<Message>> {
+        let rooms = self.rooms.lock().unwrap();
+        Ok(rooms
+            .get(room)
+            .map(|messages| {
+                messages
+                    .range(page_of(messages.len(), page, page_size))
+                    .cloned()
+                    .collect()
+            })
+            .unwrap_or_default())
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Record {
+    room: String,
+    message: Message,
+}
+
+/// History stored as one JSON record per line in a local log file. Records are written
+/// by a dedicated thread, so appending never blocks on the disk.
+pub struct FileHistoryStore {
+    memory: InMemoryHistoryStore,
+    /// Lines for the writer thread, taken when the store is dropped.
+    lines: Option<mpsc::Sender<String>>,
+    writer: Option<JoinHandle<()>>,
+}
+
+impl FileHistoryStore {
+    /// Loads the log at `path`, creating it if it does not exist yet. The newest `limit`
+    /// messages of each room are kept in memory to be served; older ones stay in the log.
+    pub fn open(path: impl Into<PathBuf>, limit: usize) -> Result<Self> {
+        let path = path.into();
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .read(true)
+            .open(&path)?;
+
+        let memory = InMemoryHistoryStore::with_limit(limit);
+        let mut reader = BufReader::new(&file);
+        let mut line = String::new();
+        let mut number = 0;
+        let mut terminated = true;
+        while reader.read_line(&mut line)? > 0 {
+            number += 1;
+            terminated = line.ends_with('\n');
+            // A crash mid-append leaves a truncated last line; losing it beats not starting.
+            match serde_json::from_str::<Record>(line.trim_end()) {
+                Ok(record) => memory.append(&record.room, &record.message)?,
+                Err(e) => tracing::warn!("Skipping line {} of {}: {}", number, path.display(), e),
+            }
+            line.clear();
+        }
+        if !terminated {
+            // Start the next record on a line of its own.
+            writeln!(&file)?;
+        }
+
+        let (lines, pending) = mpsc::channel();
+        let writer = thread::Builder::new()
+            .name("history-writer".to_string())
+            .spawn(move || write_lines(file, pending, path))?;
+        Ok(Self {
+            memory,
+            lines: Some(lines),
+            writer: Some(writer),
+        })
+    }
+}
+
+impl Drop for FileHistoryStore {
+    /// Waits for the records appended so far to be written.
+    fn drop(&mut self) {
+        self.lines.take();
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+    }
+}
+
+/// Appends each line received on `lines` to `file` until the store is dropped, flushing
+/// whenever no more lines are waiting.
+fn write_lines(file: File, lines: mpsc::Receiver<String>, path: PathBuf) {
+    let mut file = BufWriter::new(file);
+    while let Ok(line) = lines.recv() {
+        let written = std::iter::once(line)
+            .chain(lines.try_iter())
+            .try_for_each(|line| writeln!(file, "{}", line))
+            .and_then(|()| file.flush());
+        if let Err(e) = written {
+            tracing::error!("Failed to write history to {}: {}", path.display(), e);
+        }
+    }
+}
+
+impl HistoryStore for FileHistoryStore {
+    fn append(&self, room: &str, message: &Message) -> Result<()> {
+        let record = Record {
+            room: room.to_string(),
+            message: message.clone(),
+        };
+        let line = serde_json::to_string(&record)?;
+        if self
+            .lines
+            .as_ref()
+            .is_none_or(|lines| lines.send(line).is_err())
+        {
+            bail!("History writer has stopped")
+        }
+        self.memory.append(room, message)
+    }
+
+    fn page(&self, room: &str, page: usize, page_size: usize) -> Result<Vec<Message>> {
+        self.memory.page(room, page, page_size)
+    }
+}
+
+/// Positions of page `page` among `len` messages, oldest first.
+fn page_of(len: usize, page: usize, page_size: usize) -> Range<usize> {
+    let skip = page.saturating_sub(1).saturating_mul(page_size);
+    let end = len.saturating_sub(skip);
+    let start = end.saturating_sub(page_size);
+    start..end
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::{DEFAULT_HISTORY_LIMIT, FileHistoryStore, HistoryStore, InMemoryHistoryStore};
+    use utils::message::Message;
+
+    fn msg(text: &str) -> Message {
+        Message::MSG("alice".to_string(), text.to_string())
+    }
+
+    #[test]
+    fn pages_newest_first_oldest_within_page() {
+        let store = InMemoryHistoryStore::new();
+        for text in ["1", "2", "3", "4", "5"] {
+            store.append("general", &msg(text)).unwrap();
+        }
+
+        assert_eq!(
+            store.page("general", 1, 2).unwrap(),
+            vec![msg("4"), msg("5")]
+        );
+        assert_eq!(
+            store.page("general", 2, 2).unwrap(),
+            vec![msg("2"), msg("3")]
+        );
+        assert_eq!(store.page("general", 3, 2).unwrap(), vec![msg("1")]);
+        assert!(store.page("general", 4, 2).unwrap().is_empty());
+    }
+
+    #[test]
+    fn rooms_are_separate() {
+        let store = InMemoryHistoryStore::new();
+        store.append("general", &msg("hi")).unwrap();
+
+        assert!(store.page("project", 1, 10).unwrap().is_empty());
+    }
+
+    #[test]
+    fn oldest_messages_dropped_over_limit() {
+        let store = InMemoryHistoryStore::with_limit(3);
+        for text in ["1", "2", "3", "4", "5"] {
+            store.append("general", &msg(text)).unwrap();
+        }
+        store.append("project", &msg("p")).unwrap();
+
+        assert_eq!(
+            store.page("general", 1, 10).unwrap(),
+            vec![msg("3"), msg("4"), msg("5")]
+        );
+        assert_eq!(store.page("project", 1, 10).unwrap(), vec![msg("p")]);
+    }
+
+    #[test]
+    fn file_store_persists_history() {
+        let path = std::env::temp_dir().join(format!("chat-history-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let store = FileHistoryStore::open(&path, DEFAULT_HISTORY_LIMIT).unwrap();
+        store.append("general", &msg("a | b")).unwrap();
+        store.append("general", &msg("c")).unwrap();
+        drop(store);
+        let reopened = FileHistoryStore::open(&path, DEFAULT_HISTORY_LIMIT).unwrap();
+
+        assert_eq!(
+            reopened.page("general", 1, 10).unwrap(),
+            vec![msg("a | b"), msg("c")]
+        );
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn file_store_skips_corrupt_lines() {
+        let path =
+            std::env::temp_dir().join(format!("chat-history-corrupt-{}", std::process::id()));
+        let record = r#"{"room":"general","message":{"type":"MSG","data":["alice","c"]}}"#;
+        std::fs::write(&path, format!("not json\n{}\n{{\"room\":\"gen", record)).unwrap();
+
+        let store = FileHistoryStore::open(&path, DEFAULT_HISTORY_LIMIT).unwrap();
+        assert_eq!(store.page("general", 1, 10).unwrap(), vec![msg("c")]);
+        store.append("general", &msg("d")).unwrap();
+        drop(store);
+        let reopened = FileHistoryStore::open(&path, DEFAULT_HISTORY_LIMIT).unwrap();
+
+        assert_eq!(
+            reopened.page("general", 1, 10).unwrap(),
+            vec![msg("c"), msg("d")]
+        );
+        let _ = std::fs::remove_file(&path);
+    }
+}
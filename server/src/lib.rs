@@ -1,2 +1,13 @@
+pub mod admin;
+pub mod config;
+pub mod connection;
+pub mod credentials;
+pub mod history;
+pub mod metrics;
+pub mod queue;
+pub mod ratelimit;
+pub mod registry;
 pub mod room;
 pub mod server;
+pub mod session;
+pub mod websocket;
@@ -1,7 +1,31 @@
 use clap::Parser;
-use server::server::ServerChat;
-use std::sync::Arc;
-use tokio::net::TcpListener;
+use server::{
+    admin,
+    config::ServerConfig,
+    credentials::{CredentialStore, FileCredentialStore, InMemoryCredentialStore},
+    history::{DEFAULT_HISTORY_LIMIT, FileHistoryStore, HistoryStore, InMemoryHistoryStore},
+    queue::SlowConsumerPolicy,
+    ratelimit::{Rate, RateLimitConfig},
+    server::ServerChat,
+};
+use std::{net::SocketAddr, path::PathBuf, sync::Arc, time::Duration};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpListener,
+    time,
+};
+use tokio_rustls::TlsAcceptor;
+use utils::{
+    codec::WireFormat,
+    protocol::{DEFAULT_MAX_LINE_LENGTH, DEFAULT_MAX_TEXT_LENGTH, LEGACY_PROTOCOL_VERSION},
+    tls,
+};
+
+/// How long connected clients get to receive their remaining frames on shutdown.
+const SHUTDOWN_DEADLINE: Duration = Duration::from_secs(5);
+
+/// How often rate limits of senders that went quiet are forgotten.
+const RATE_LIMIT_PRUNE_INTERVAL: Duration = Duration::from_secs(60);
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
@@ -11,18 +35,201 @@ async fn main() -> anyhow::Result<()> {
         .with_max_level(tracing::Level::INFO)
         .init();
     let listener = TcpListener::bind(format!("127.0.0.1:{}", args.port)).await?;
-    let server = Arc::new(ServerChat::new());
-    tracing::info!("Server running on 127.0.0.1:{}", args.port);
+    let credentials: Arc<dyn CredentialStore> = match &args.accounts {
+        Some(path) => Arc::new(FileCredentialStore::open(path)?),
+        None => Arc::new(InMemoryCredentialStore::new()),
+    };
+    let history: Arc<dyn HistoryStore> = match &args.history {
+        Some(path) => Arc::new(FileHistoryStore::open(path, args.history_limit)?),
+        None => Arc::new(InMemoryHistoryStore::with_limit(args.history_limit)),
+    };
+    let acceptor = match (&args.tls_cert, &args.tls_key) {
+        (Some(cert), Some(key)) => Some(tls::acceptor(cert, key, args.tls_client_ca.as_deref())?),
+        _ => None,
+    };
+    let config = ServerConfig {
+        min_protocol_version: args.min_protocol_version,
+        history_replay: args.history_replay,
+        client_queue_capacity: args.queue_capacity,
+        slow_consumer_policy: args.slow_consumer_policy,
+        heartbeat_interval: Duration::from_secs(args.heartbeat_interval),
+        idle_timeout: Duration::from_secs(args.idle_timeout),
+        resume_grace: Duration::from_secs(args.resume_grace),
+        rate_limit: RateLimitConfig {
+            per_user: Rate {
+                messages_per_sec: limit(args.user_message_rate),
+                bytes_per_sec: limit(args.user_byte_rate),
+            },
+            per_address: Rate {
+                messages_per_sec: limit(args.ip_message_rate),
+                bytes_per_sec: limit(args.ip_byte_rate),
+            },
+            strikes: args.rate_limit_strikes,
+        },
+        max_line_length: args.max_line_length,
+        max_text_length: args.max_text_length,
+    };
+    let server = Arc::new(ServerChat::with_config(config, credentials, history));
+    tracing::info!(
+        "Server running on 127.0.0.1:{}{}",
+        args.port,
+        if acceptor.is_some() { " (TLS)" } else { "" }
+    );
+
+    let json = match args.json_port {
+        Some(port) => {
+            let json_listener = TcpListener::bind(format!("127.0.0.1:{}", port)).await?;
+            tracing::info!("JSON listener on 127.0.0.1:{}", port);
+            Some(tokio::spawn(serve(
+                json_listener,
+                Arc::clone(&server),
+                acceptor.clone(),
+                Transport::Lines(WireFormat::Json),
+            )))
+        }
+        None => None,
+    };
+    let websocket = match args.ws_port {
+        Some(port) => {
+            let ws_listener = TcpListener::bind(format!("127.0.0.1:{}", port)).await?;
+            tracing::info!("WebSocket listener on 127.0.0.1:{}", port);
+            Some(tokio::spawn(serve(
+                ws_listener,
+                Arc::clone(&server),
+                acceptor.clone(),
+                Transport::WebSocket(WireFormat::Json),
+            )))
+        }
+        None => None,
+    };
+
+    let admin = match args.admin_port {
+        Some(port) => {
+            let admin_listener = TcpListener::bind(format!("127.0.0.1:{}", port)).await?;
+            tracing::info!("Admin API on http://127.0.0.1:{}", port);
+            Some(tokio::spawn(admin::serve(
+                admin_listener,
+                Arc::clone(&server),
+            )))
+        }
+        None => None,
+    };
+
+    let pruner = tokio::spawn(prune_rate_limits(Arc::clone(&server)));
+
+    tokio::select! {
+        () = serve(listener, Arc::clone(&server), acceptor, Transport::Lines(WireFormat::Pipe)) => {}
+        signal = shutdown_signal() => tracing::info!("Received {}, shutting down", signal?),
+    }
+    for listener in [json, websocket].into_iter().flatten() {
+        listener.abort();
+    }
+    pruner.abort();
+    if let Some(admin) = admin {
+        admin.abort();
+    }
+
+    if !server.close(args.shutdown_reason, SHUTDOWN_DEADLINE).await {
+        tracing::warn!("Some clients were not drained before the deadline");
+    }
+
+    Ok(())
+}
+
+/// Reads a rate from the command line, where 0 means unlimited.
+fn limit(rate: u32) -> Option<u32> {
+    (rate > 0).then_some(rate)
+}
 
-    while let Ok((stream, _addr)) = listener.accept().await {
+/// Resolves with the name of the first SIGINT or SIGTERM received.
+#[cfg(unix)]
+async fn shutdown_signal() -> anyhow::Result<&'static str> {
+    use tokio::signal::unix::{SignalKind, signal};
+
+    let mut terminate = signal(SignalKind::terminate())?;
+    tokio::select! {
+        result = tokio::signal::ctrl_c() => result.map(|()| "SIGINT").map_err(Into::into),
+        _ = terminate.recv() => Ok("SIGTERM"),
+    }
+}
+
+/// Resolves once Ctrl+C is pressed, the only shutdown signal outside Unix.
+#[cfg(not(unix))]
+async fn shutdown_signal() -> anyhow::Result<&'static str> {
+    tokio::signal::ctrl_c().await?;
+    Ok("Ctrl+C")
+}
+
+/// Prunes `server`'s rate limits every [`RATE_LIMIT_PRUNE_INTERVAL`].
+async fn prune_rate_limits(server: Arc<ServerChat>) {
+    let mut interval = time::interval(RATE_LIMIT_PRUNE_INTERVAL);
+    loop {
+        interval.tick().await;
+        server.prune_rate_limits();
+    }
+}
+
+/// What a listener's connections speak once accepted.
+#[derive(Clone, Copy)]
+enum Transport {
+    /// One message per line.
+    Lines(WireFormat),
+    /// One message per WebSocket text frame.
+    WebSocket(WireFormat),
+}
+
+/// Serves one connection accepted from `peer` over `transport`.
+async fn connection<S>(
+    server: &ServerChat,
+    stream: S,
+    peer: SocketAddr,
+    transport: Transport,
+) -> anyhow::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    match transport {
+        Transport::Lines(format) => server.new_connection(stream, peer, format).await,
+        Transport::WebSocket(format) => server.new_websocket_connection(stream, peer, format).await,
+    }
+}
+
+/// Accepts connections on `listener` until it fails, serving each one over `transport`.
+/// A TLS handshake must finish within the idle timeout.
+async fn serve(
+    listener: TcpListener,
+    server: Arc<ServerChat>,
+    acceptor: Option<TlsAcceptor>,
+    transport: Transport,
+) {
+    while let Ok((stream, addr)) = listener.accept().await {
         let server_clone = Arc::clone(&server);
+        let acceptor = acceptor.clone();
 
         tokio::spawn(async move {
-            let _ = server_clone.new_connection(stream).await;
+            match acceptor {
+                Some(acceptor) => {
+                    let timeout = server_clone.config().idle_timeout;
+                    match time::timeout(timeout, acceptor.accept(stream)).await {
+                        Ok(Ok(stream)) => {
+                            let _ = connection(&server_clone, stream, addr, transport).await;
+                        }
+                        Ok(Err(e)) => tracing::warn!("TLS handshake with {} failed: {}", addr, e),
+                        Err(_) => {
+                            tracing::warn!(
+                                "Dropping {}: no TLS handshake within {:?}",
+                                addr,
+                                timeout
+                            )
+                        }
+                    }
+                }
+                None => {
+                    let _ = connection(&server_clone, stream, addr, transport).await;
+                }
+            }
         });
     }
-
-    Ok(())
 }
 
 #[derive(Parser, Debug)]
@@ -30,4 +237,77 @@ struct Args {
     /// Port to listen on
     #[arg(short, long)]
     port: u16,
+    /// Additional port speaking JSON lines instead of the pipe format
+    #[arg(long)]
+    json_port: Option<u16>,
+    /// Additional port accepting WebSocket connections, one JSON message per text frame
+    #[arg(long)]
+    ws_port: Option<u16>,
+    /// Port serving a read-only HTTP API with JSON about users, rooms and connections
+    #[arg(long)]
+    admin_port: Option<u16>,
+    /// File holding registered accounts. Accounts are kept in memory only when omitted
+    #[arg(long)]
+    accounts: Option<PathBuf>,
+    /// Log file for room history. History is kept in memory only when omitted
+    #[arg(long)]
+    history: Option<PathBuf>,
+    /// Number of past messages replayed on entering a room, and per `history` page
+    #[arg(long, default_value_t = 20)]
+    history_replay: usize,
+    /// Number of past messages per room kept to be replayed; older ones stay in --history
+    #[arg(long, default_value_t = DEFAULT_HISTORY_LIMIT)]
+    history_limit: usize,
+    /// PEM certificate chain; enables TLS together with --tls-key
+    #[arg(long, requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+    /// PEM private key for --tls-cert
+    #[arg(long, requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+    /// PEM CA certificates; when set, clients must present a certificate signed by them
+    #[arg(long, requires = "tls_cert")]
+    tls_client_ca: Option<PathBuf>,
+    /// Oldest protocol version to accept. Version 1 clients log in without a handshake
+    #[arg(long, default_value_t = LEGACY_PROTOCOL_VERSION)]
+    min_protocol_version: u16,
+    /// Messages queued for each client before --slow-consumer-policy applies
+    #[arg(long, default_value_t = 1024)]
+    queue_capacity: usize,
+    /// What to do with a client whose queue is full
+    #[arg(long, value_enum, default_value_t)]
+    slow_consumer_policy: SlowConsumerPolicy,
+    /// Seconds between PINGs sent to each client
+    #[arg(long, default_value_t = 30, value_parser = clap::value_parser!(u64).range(1..))]
+    heartbeat_interval: u64,
+    /// Seconds of silence after which a client is disconnected
+    #[arg(long, default_value_t = 90, value_parser = clap::value_parser!(u64).range(1..))]
+    idle_timeout: u64,
+    /// Seconds a dropped client may take to reconnect and resume its session
+    #[arg(long, default_value_t = 30)]
+    resume_grace: u64,
+    /// Messages each user may send per second, 0 for no limit
+    #[arg(long, default_value_t = 10)]
+    user_message_rate: u32,
+    /// Bytes each user may send per second, 0 for no limit
+    #[arg(long, default_value_t = 16 * 1024)]
+    user_byte_rate: u32,
+    /// Messages each source address may send per second, 0 for no limit
+    #[arg(long, default_value_t = 50)]
+    ip_message_rate: u32,
+    /// Bytes each source address may send per second, 0 for no limit
+    #[arg(long, default_value_t = 64 * 1024)]
+    ip_byte_rate: u32,
+    /// Rate-limited messages, each within 10 seconds of the last, after which a client is
+    /// disconnected; 0 never disconnects
+    #[arg(long, default_value_t = 10)]
+    rate_limit_strikes: u32,
+    /// Longest line or WebSocket message, in bytes, accepted from a client
+    #[arg(long, default_value_t = DEFAULT_MAX_LINE_LENGTH)]
+    max_line_length: usize,
+    /// Longest message text, in bytes, accepted from a client
+    #[arg(long, default_value_t = DEFAULT_MAX_TEXT_LENGTH)]
+    max_text_length: usize,
+    /// Reason sent to connected clients when the server shuts down
+    #[arg(long)]
+    shutdown_reason: Option<String>,
 }
@@ -0,0 +1,151 @@
+use prometheus::{
+    Histogram, HistogramOpts, IntCounter, IntGauge, Registry, TextEncoder, core::Collector,
+    exponential_buckets,
+};
+
+/// Load on a server, exported in the Prometheus text format. Each server keeps its own
+/// registry so several can run in one process.
+pub struct Metrics {
+    registry: Registry,
+    /// Connections accepted on any listener.
+    pub accepted: IntCounter,
+    /// Connections that ended before logging in.
+    pub auth_failures: IntCounter,
+    /// Connections open right now, set when the metrics are rendered.
+    pub active_connections: IntGauge,
+    /// Number of clients each broadcast was queued for.
+    pub fanout: Histogram,
+    /// Messages received from clients.
+    pub messages_in: IntCounter,
+    /// Messages written to clients.
+    pub messages_out: IntCounter,
+    /// Messages waiting in client queues, set when the metrics are rendered.
+    pub queued: IntGauge,
+    /// Longest client queue, set when the metrics are rendered.
+    pub max_queue_depth: IntGauge,
+    /// Messages discarded because a client fell behind.
+    pub dropped: IntCounter,
+    /// Clients whose new messages are being discarded until they catch up, set when the
+    /// metrics are rendered.
+    pub lagging: IntGauge,
+    /// Frames refused for exceeding a rate limit.
+    pub rate_limited: IntCounter,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let registry = Registry::new();
+        let fanout = HistogramOpts::new(
+            "chat_broadcast_fanout",
+            "Number of clients each broadcast was queued for",
+        )
+        .buckets(exponential_buckets(1.0, 2.0, 12).expect("valid buckets"));
+        Metrics {
+            accepted: register(
+                &registry,
+                IntCounter::new(
+                    "chat_connections_accepted_total",
+                    "Connections accepted on any listener",
+                ),
+            ),
+            auth_failures: register(
+                &registry,
+                IntCounter::new(
+                    "chat_auth_failures_total",
+                    "Connections that ended before logging in",
+                ),
+            ),
+            active_connections: register(
+                &registry,
+                IntGauge::new("chat_active_connections", "Connections open right now"),
+            ),
+            fanout: register(&registry, Histogram::with_opts(fanout)),
+            messages_in: register(
+                &registry,
+                IntCounter::new(
+                    "chat_messages_received_total",
+                    "Messages received from clients",
+                ),
+            ),
+            messages_out: register(
+                &registry,
+                IntCounter::new("chat_messages_sent_total", "Messages written to clients"),
+            ),
+            queued: register(
+                &registry,
+                IntGauge::new("chat_queued_messages", "Messages waiting in client queues"),
+            ),
+            max_queue_depth: register(
+                &registry,
+                IntGauge::new("chat_max_queue_depth", "Longest client queue"),
+            ),
+            dropped: register(
+                &registry,
+                IntCounter::new(
+                    "chat_dropped_messages_total",
+                    "Messages discarded because a client fell behind",
+                ),
+            ),
+            lagging: register(
+                &registry,
+                IntGauge::new(
+                    "chat_lagging_clients",
+                    "Clients whose new messages are being discarded until they catch up",
+                ),
+            ),
+            rate_limited: register(
+                &registry,
+                IntCounter::new(
+                    "chat_rate_limited_total",
+                    "Frames refused for exceeding a rate limit",
+                ),
+            ),
+            registry,
+        }
+    }
+
+    /// Every metric in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        TextEncoder::new()
+            .encode_to_string(&self.registry.gather())
+            .expect("metrics always encode as text")
+    }
+}
+
+/// Adds `metric` to `registry`. The metrics are fixed and uniquely named, so failing
+/// here is a bug.
+fn register<M>(registry: &Registry, metric: prometheus::Result<M>) -> M
+where
+    M: Collector + Clone + 'static,
+{
+    let metric = metric.expect("valid metric");
+    registry
+        .register(Box::new(metric.clone()))
+        .expect("metric registered once");
+    metric
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::Metrics;
+
+    #[test]
+    fn renders_text_format() {
+        let metrics = Metrics::new();
+        metrics.accepted.inc();
+        metrics.fanout.observe(3.0);
+
+        let text = metrics.render();
+        assert!(text.contains("# TYPE chat_connections_accepted_total counter"));
+        assert!(text.contains("chat_connections_accepted_total 1"));
+        assert!(text.contains("chat_broadcast_fanout_bucket{le=\"4\"} 1"));
+        assert!(text.contains("chat_dropped_messages_total 0"));
+    }
+}
@@ -0,0 +1,380 @@
+use anyhow::{Result, bail};
+use prometheus::IntCounter;
+use std::{
+    collections::VecDeque,
+    fmt,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+use tokio::sync::Notify;
+use tokio_util::sync::CancellationToken;
+use utils::codec::Frame;
+
+/// What to do when a client's outgoing queue is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum SlowConsumerPolicy {
+    /// Discard the oldest queued message to make room for the new one.
+    #[default]
+    DropOldest,
+    /// Disconnect the client.
+    Disconnect,
+    /// Discard new messages and flag the client as lagging until it catches up.
+    MarkLagging,
+}
+
+struct Shared {
+    state: Mutex<State>,
+    capacity: usize,
+    policy: SlowConsumerPolicy,
+    /// Wakes the receiver when a message is queued or the last sender goes away.
+    ready: Notify,
+    /// Cancelled once the receiver is gone or the client was disconnected for lagging.
+    closed: CancellationToken,
+    dropped: AtomicU64,
+    /// Server-wide count of discarded messages, if kept.
+    total_dropped: Option<IntCounter>,
+}
+
+struct State {
+    messages: VecDeque<Arc<Frame>>,
+    senders: usize,
+    lagging: bool,
+}
+
+/// Creates the outgoing queue of one client, holding at most `capacity` messages.
+pub fn channel(capacity: usize, policy: SlowConsumerPolicy) -> (ClientSender, ClientReceiver) {
+    new_channel(capacity, policy, None)
+}
+
+/// Like [`channel`], also adding every discarded message to `total_dropped`.
+pub fn counted_channel(
+    capacity: usize,
+    policy: SlowConsumerPolicy,
+    total_dropped: IntCounter,
+) -> (ClientSender, ClientReceiver) {
+    new_channel(capacity, policy, Some(total_dropped))
+}
+
+fn new_channel(
+    capacity: usize,
+    policy: SlowConsumerPolicy,
+    total_dropped: Option<IntCounter>,
+) -> (ClientSender, ClientReceiver) {
+    let shared = Arc::new(Shared {
+        state: Mutex::new(State {
+            messages: VecDeque::with_capacity(capacity),
+            senders: 1,
+            lagging: false,
+        }),
+        capacity: capacity.max(1),
+        policy,
+        ready: Notify::new(),
+        closed: CancellationToken::new(),
+        dropped: AtomicU64::new(0),
+        total_dropped,
+    });
+    (
+        ClientSender {
+            shared: Arc::clone(&shared),
+        },
+        ClientReceiver { shared },
+    )
+}
+
+/// Sending half of a client's outgoing queue. Never blocks: a full queue is handled
+/// according to its [`SlowConsumerPolicy`].
+pub struct ClientSender {
+    shared: Arc<Shared>,
+}
+
+impl ClientSender {
+    /// Queues `frame`, failing only if the client is gone or was just disconnected.
+    pub fn send(&self, frame: impl Into<Arc<Frame>>) -> Result<()> {
+        if self.shared.closed.is_cancelled() {
+            bail!("Client queue is closed")
+        }
+
+        let mut state = self.shared.state.lock().unwrap();
+        if state.messages.len() >= self.shared.capacity {
+            self.shared.dropped.fetch_add(1, Ordering::Relaxed);
+            if let Some(total_dropped) = &self.shared.total_dropped {
+                total_dropped.inc();
+            }
+            match self.shared.policy {
+                SlowConsumerPolicy::DropOldest => {
+                    state.messages.pop_front();
+                }
+                SlowConsumerPolicy::Disconnect => {
+                    drop(state);
+                    self.shared.closed.cancel();
+                    self.shared.ready.notify_one();
+                    bail!("Client fell behind and was disconnected")
+                }
+                SlowConsumerPolicy::MarkLagging => {
+                    state.lagging = true;
+                    return Ok(());
+                }
+            }
+        }
+        state.messages.push_back(frame.into());
+        drop(state);
+        self.shared.ready.notify_one();
+        Ok(())
+    }
+
+    /// Number of messages discarded because the client fell behind.
+    pub fn dropped(&self) -> u64 {
+        self.shared.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Number of messages waiting to be written.
+    pub fn len(&self) -> usize {
+        self.shared.state.lock().unwrap().messages.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Whether new messages are being discarded until the client catches up.
+    pub fn is_lagging(&self) -> bool {
+        self.shared.state.lock().unwrap().lagging
+    }
+
+    /// A view of this queue for reporting, which does not keep it open like a sender.
+    pub fn stats(&self) -> QueueStats {
+        QueueStats {
+            shared: Arc::clone(&self.shared),
+        }
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.shared.closed.is_cancelled()
+    }
+
+    /// Resolves once the receiver is gone or the client was disconnected for lagging.
+    pub async fn closed(&self) {
+        self.shared.closed.cancelled().await
+    }
+}
+
+impl Clone for ClientSender {
+    fn clone(&self) -> Self {
+        self.shared.state.lock().unwrap().senders += 1;
+        Self {
+            shared: Arc::clone(&self.shared),
+        }
+    }
+}
+
+impl Drop for ClientSender {
+    fn drop(&mut self) {
+        let mut state = self.shared.state.lock().unwrap();
+        state.senders -= 1;
+        if state.senders == 0 {
+            drop(state);
+            self.shared.ready.notify_one();
+        }
+    }
+}
+
+/// Read-only view of a client's outgoing queue.
+#[derive(Clone)]
+pub struct QueueStats {
+    shared: Arc<Shared>,
+}
+
+impl QueueStats {
+    /// Number of messages discarded because the client fell behind.
+    pub fn dropped(&self) -> u64 {
+        self.shared.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Whether new messages are being discarded until the client catches up.
+    pub fn is_lagging(&self) -> bool {
+        self.shared.state.lock().unwrap().lagging
+    }
+}
+
+impl fmt::Debug for QueueStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("QueueStats")
+            .field("dropped", &self.dropped())
+            .field("lagging", &self.is_lagging())
+            .finish()
+    }
+}
+
+/// Receiving half of a client's outgoing queue, drained by its writer task.
+pub struct ClientReceiver {
+    shared: Arc<Shared>,
+}
+
+impl ClientReceiver {
+    /// Waits for the next message. Returns `None` once every sender is dropped and
+    /// the queue is empty, or straight away if the client was disconnected.
+    pub async fn recv(&mut self) -> Option<Arc<Frame>> {
+        loop {
+            if self.shared.closed.is_cancelled() {
+                return None;
+            }
+            let ready = self.shared.ready.notified();
+            tokio::pin!(ready);
+            {
+                // Popping, checking for senders and waiting for the next wakeup all start
+                // under one lock, so a send followed by the last sender's drop is not missed.
+                let mut state = self.shared.state.lock().unwrap();
+                if let Some(frame) = pop(&mut state) {
+                    return Some(frame);
+                }
+                if state.senders == 0 {
+                    return None;
+                }
+                ready.as_mut().enable();
+            }
+            ready.await;
+        }
+    }
+
+    /// Takes the next queued message without waiting.
+    pub fn try_recv(&mut self) -> Option<Arc<Frame>> {
+        pop(&mut self.shared.state.lock().unwrap())
+    }
+}
+
+fn pop(state: &mut State) -> Option<Arc<Frame>> {
+    let frame = state.messages.pop_front();
+    if state.messages.is_empty() {
+        state.lagging = false;
+    }
+    frame
+}
+
+impl Drop for ClientReceiver {
+    fn drop(&mut self) {
+        self.shared.closed.cancel();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::{ClientReceiver, SlowConsumerPolicy, channel, counted_channel};
+    use futures::executor::block_on;
+    use prometheus::IntCounter;
+    use std::{
+        sync::{Arc, Barrier},
+        thread,
+    };
+    use utils::message::Message;
+
+    fn join(name: &str) -> Message {
+        Message::JOIN(name.to_string())
+    }
+
+    async fn recv(rx: &mut ClientReceiver) -> Option<Message> {
+        rx.recv().await.map(|frame| frame.message().clone())
+    }
+
+    #[tokio::test]
+    async fn drop_oldest_keeps_newest() {
+        let (tx, mut rx) = channel(2, SlowConsumerPolicy::DropOldest);
+        for name in ["a", "b", "c"] {
+            tx.send(join(name)).unwrap();
+        }
+
+        assert_eq!(tx.dropped(), 1);
+        assert_eq!(recv(&mut rx).await, Some(join("b")));
+        assert_eq!(recv(&mut rx).await, Some(join("c")));
+    }
+
+    #[tokio::test]
+    async fn counted_channel_adds_to_total() {
+        let total = IntCounter::new("dropped", "dropped").unwrap();
+        let (tx1, _rx1) = counted_channel(1, SlowConsumerPolicy::DropOldest, total.clone());
+        let (tx2, _rx2) = counted_channel(1, SlowConsumerPolicy::MarkLagging, total.clone());
+        for name in ["a", "b", "c"] {
+            tx1.send(join(name)).unwrap();
+            tx2.send(join(name)).unwrap();
+        }
+
+        assert_eq!(total.get(), 4);
+        assert_eq!(tx1.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn disconnect_closes_queue() {
+        let (tx, mut rx) = channel(1, SlowConsumerPolicy::Disconnect);
+        tx.send(join("a")).unwrap();
+
+        assert!(tx.send(join("b")).is_err());
+        assert!(tx.is_closed());
+        assert_eq!(recv(&mut rx).await, None);
+    }
+
+    #[tokio::test]
+    async fn mark_lagging_until_caught_up() {
+        let (tx, mut rx) = channel(1, SlowConsumerPolicy::MarkLagging);
+        tx.send(join("a")).unwrap();
+        tx.send(join("b")).unwrap();
+
+        assert!(tx.is_lagging());
+        assert_eq!(tx.dropped(), 1);
+        assert_eq!(recv(&mut rx).await, Some(join("a")));
+        assert!(!tx.is_lagging());
+    }
+
+    #[tokio::test]
+    async fn receiver_drains_after_senders_dropped() {
+        let (tx, mut rx) = channel(4, SlowConsumerPolicy::DropOldest);
+        tx.send(join("a")).unwrap();
+        drop(tx);
+
+        assert_eq!(recv(&mut rx).await, Some(join("a")));
+        assert_eq!(recv(&mut rx).await, None);
+    }
+
+    #[tokio::test]
+    async fn stats_do_not_keep_queue_open() {
+        let (tx, mut rx) = channel(1, SlowConsumerPolicy::MarkLagging);
+        let stats = tx.stats();
+        tx.send(join("a")).unwrap();
+        tx.send(join("b")).unwrap();
+        drop(tx);
+
+        assert!(stats.is_lagging());
+        assert_eq!(stats.dropped(), 1);
+        assert_eq!(recv(&mut rx).await, Some(join("a")));
+        assert_eq!(recv(&mut rx).await, None);
+        assert!(!stats.is_lagging());
+    }
+
+    #[test]
+    fn last_frame_received_when_sender_drops_concurrently() {
+        for _ in 0..1000 {
+            let (tx, mut rx) = channel(4, SlowConsumerPolicy::DropOldest);
+            let start = Arc::new(Barrier::new(2));
+            let sender = thread::spawn({
+                let start = Arc::clone(&start);
+                move || {
+                    start.wait();
+                    tx.send(join("bye")).unwrap();
+                    drop(tx);
+                }
+            });
+
+            start.wait();
+            let received = block_on(async {
+                let mut received = Vec::new();
+                while let Some(message) = recv(&mut rx).await {
+                    received.push(message);
+                }
+                received
+            });
+            sender.join().unwrap();
+            assert_eq!(received, vec![join("bye")]);
+        }
+    }
+}
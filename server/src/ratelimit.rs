@@ -0,0 +1,300 @@
+use dashmap::DashMap;
+use std::{hash::Hash, net::IpAddr, time::Duration};
+use tokio::time::Instant;
+
+/// How long a rate-limited client must behave before its strikes are forgiven.
+const STRIKE_WINDOW: Duration = Duration::from_secs(10);
+
+/// Messages and bytes a sender may send per second, with bursts of up to one second's
+/// worth. `None` leaves that dimension unlimited.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Rate {
+    pub messages_per_sec: Option<u32>,
+    pub bytes_per_sec: Option<u32>,
+}
+
+/// Limits on what logged-in clients send.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RateLimitConfig {
+    /// Applied to each account, across all of its connections.
+    pub per_user: Rate,
+    /// Applied to each source address, across all of its connections.
+    pub per_address: Rate,
+    /// Rate-limited frames within [`STRIKE_WINDOW`] of each other before the client is
+    /// disconnected.
+    pub strikes: u32,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            per_user: Rate {
+                messages_per_sec: Some(10),
+                bytes_per_sec: Some(16 * 1024),
+            },
+            per_address: Rate {
+                messages_per_sec: Some(50),
+                bytes_per_sec: Some(64 * 1024),
+            },
+            strikes: 10,
+        }
+    }
+}
+
+/// Tokens refilled continuously at `rate` per second, holding at most `rate`.
+#[derive(Debug)]
+struct TokenBucket {
+    rate: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: u32, now: Instant) -> Self {
+        TokenBucket {
+            rate: rate as f64,
+            tokens: rate as f64,
+            updated: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.updated = now;
+    }
+
+    fn is_full(&self) -> bool {
+        self.tokens >= self.rate
+    }
+}
+
+/// The message and byte buckets of one sender.
+#[derive(Debug)]
+struct Buckets {
+    messages: Option<TokenBucket>,
+    bytes: Option<TokenBucket>,
+}
+
+impl Buckets {
+    fn new(rate: Rate, now: Instant) -> Self {
+        Buckets {
+            messages: rate
+                .messages_per_sec
+                .map(|rate| TokenBucket::new(rate, now)),
+            bytes: rate.bytes_per_sec.map(|rate| TokenBucket::new(rate, now)),
+        }
+    }
+
+    /// Refills the buckets and tells whether they hold enough for a frame of `bytes`.
+    /// A frame larger than a whole second's worth of bytes goes through once the bucket
+    /// is full, so it is slowed down rather than refused forever.
+    fn allows(&mut self, bytes: f64, now: Instant) -> bool {
+        let mut allowed = true;
+        if let Some(messages) = &mut self.messages {
+            messages.refill(now);
+            allowed &= messages.tokens >= 1.0;
+        }
+        if let Some(bytes_bucket) = &mut self.bytes {
+            bytes_bucket.refill(now);
+            allowed &= bytes_bucket.tokens >= bytes.min(bytes_bucket.rate);
+        }
+        allowed
+    }
+
+    fn take(&mut self, bytes: f64) {
+        if let Some(messages) = &mut self.messages {
+            messages.tokens -= 1.0;
+        }
+        if let Some(bytes_bucket) = &mut self.bytes {
+            bytes_bucket.tokens -= bytes;
+        }
+    }
+
+    fn is_full(&mut self, now: Instant) -> bool {
+        [&mut self.messages, &mut self.bytes]
+            .into_iter()
+            .flatten()
+            .all(|bucket| {
+                bucket.refill(now);
+                bucket.is_full()
+            })
+    }
+}
+
+/// Token buckets per account and per source address.
+#[derive(Debug)]
+pub struct RateLimiter {
+    config: RateLimitConfig,
+    users: DashMap<String, Buckets>,
+    addresses: DashMap<IpAddr, Buckets>,
+}
+
+impl RateLimiter {
+    pub fn new(config: RateLimitConfig) -> Self {
+        RateLimiter {
+            config,
+            users: DashMap::new(),
+            addresses: DashMap::new(),
+        }
+    }
+
+    /// Charges a frame of `bytes` to `account` and to `address`. Returns `false`, charging
+    /// neither, if either of them is over its limit.
+    pub fn check(&self, account: &str, address: IpAddr, bytes: usize) -> bool {
+        let now = Instant::now();
+        let bytes = bytes as f64;
+        let mut user = self
+            .users
+            .entry(account.to_string())
+            .or_insert_with(|| Buckets::new(self.config.per_user, now));
+        let mut source = self
+            .addresses
+            .entry(address)
+            .or_insert_with(|| Buckets::new(self.config.per_address, now));
+        if !(user.allows(bytes, now) & source.allows(bytes, now)) {
+            return false;
+        }
+        user.take(bytes);
+        source.take(bytes);
+        true
+    }
+
+    /// Forgets senders whose buckets have refilled, as new ones would start out the same.
+    pub fn prune(&self) {
+        let now = Instant::now();
+        prune(&self.users, now);
+        prune(&self.addresses, now);
+    }
+
+    pub fn strikes(&self) -> Strikes {
+        Strikes {
+            limit: self.config.strikes,
+            count: 0,
+            last: Instant::now(),
+        }
+    }
+}
+
+fn prune<K: Eq + Hash>(buckets: &DashMap<K, Buckets>, now: Instant) {
+    buckets.retain(|_, buckets| !buckets.is_full(now));
+}
+
+/// Counts a connection's rate-limited frames to spot clients that ignore `RATE_LIMITED`.
+#[derive(Debug)]
+pub struct Strikes {
+    limit: u32,
+    count: u32,
+    last: Instant,
+}
+
+impl Strikes {
+    /// Records a rate-limited frame. Returns `true` once the client has had too many in
+    /// a row and should be disconnected.
+    pub fn strike(&mut self) -> bool {
+        let now = Instant::now();
+        if now.duration_since(self.last) > STRIKE_WINDOW {
+            self.count = 0;
+        }
+        self.count += 1;
+        self.last = now;
+        self.limit > 0 && self.count >= self.limit
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::{Rate, RateLimitConfig, RateLimiter};
+    use std::{net::IpAddr, time::Duration};
+
+    const HOME: IpAddr = IpAddr::V4(std::net::Ipv4Addr::LOCALHOST);
+    const AWAY: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(192, 0, 2, 1));
+
+    fn limiter(per_user: Rate, per_address: Rate) -> RateLimiter {
+        RateLimiter::new(RateLimitConfig {
+            per_user,
+            per_address,
+            strikes: 3,
+        })
+    }
+
+    fn messages(per_sec: u32) -> Rate {
+        Rate {
+            messages_per_sec: Some(per_sec),
+            bytes_per_sec: None,
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn user_bucket_refills() {
+        let limiter = limiter(messages(2), Rate::default());
+        assert!(limiter.check("alice", HOME, 10));
+        assert!(limiter.check("alice", AWAY, 10));
+        assert!(!limiter.check("alice", HOME, 10));
+        assert!(limiter.check("bob", HOME, 10));
+
+        tokio::time::advance(Duration::from_millis(500)).await;
+        assert!(limiter.check("alice", HOME, 10));
+        assert!(!limiter.check("alice", HOME, 10));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn address_bucket_shared_by_users() {
+        let limiter = limiter(Rate::default(), messages(2));
+        assert!(limiter.check("alice", HOME, 10));
+        assert!(limiter.check("bob", HOME, 10));
+        assert!(!limiter.check("carol", HOME, 10));
+        assert!(limiter.check("carol", AWAY, 10));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn bytes_limited() {
+        let bytes = Rate {
+            messages_per_sec: None,
+            bytes_per_sec: Some(100),
+        };
+        let limiter = limiter(bytes, Rate::default());
+        assert!(limiter.check("alice", HOME, 60));
+        assert!(!limiter.check("alice", HOME, 60));
+        assert!(limiter.check("alice", HOME, 40));
+
+        // Larger than the bucket: allowed once it is full again
+        tokio::time::advance(Duration::from_secs(1)).await;
+        assert!(limiter.check("alice", HOME, 500));
+        assert!(!limiter.check("alice", HOME, 1));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn refused_frame_charges_nothing() {
+        let limiter = limiter(messages(1), messages(2));
+        assert!(limiter.check("alice", HOME, 10));
+        assert!(!limiter.check("alice", HOME, 10));
+        // Alice's refused frame left the address bucket one token for bob
+        assert!(limiter.check("bob", HOME, 10));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn prune_forgets_idle_senders() {
+        let limiter = limiter(messages(1), messages(1));
+        assert!(limiter.check("alice", HOME, 10));
+        limiter.prune();
+        assert_eq!(limiter.users.len(), 1);
+
+        tokio::time::advance(Duration::from_secs(1)).await;
+        limiter.prune();
+        assert!(limiter.users.is_empty() && limiter.addresses.is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn strikes_forgiven_after_window() {
+        let limiter = limiter(Rate::default(), Rate::default());
+        let mut strikes = limiter.strikes();
+        assert!(!strikes.strike());
+        assert!(!strikes.strike());
+        tokio::time::advance(Duration::from_secs(11)).await;
+        assert!(!strikes.strike());
+        assert!(!strikes.strike());
+        assert!(strikes.strike());
+    }
+}
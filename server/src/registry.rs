@@ -0,0 +1,173 @@
+use crate::{queue::ClientSender, room::Room};
+use anyhow::{Result, bail};
+use std::{collections::HashMap, sync::Arc};
+use tokio::sync::Mutex;
+
+pub use utils::protocol::DEFAULT_ROOM;
+
+/// Named rooms hosted by the server, created on demand and dropped once empty.
+pub struct RoomRegistry {
+    rooms: Mutex<HashMap<String, Arc<Room>>>,
+}
+
+impl Default for RoomRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RoomRegistry {
+    pub fn new() -> Self {
+        let mut rooms = HashMap::new();
+        rooms.insert(DEFAULT_ROOM.to_string(), Arc::new(Room::new()));
+        RoomRegistry {
+            rooms: Mutex::new(rooms),
+        }
+    }
+
+    /// Adds `username` to the room called `name`, creating the room if needed.
+    pub async fn join(
+        &self,
+        name: &str,
+        username: String,
+        sender: ClientSender,
+    ) -> Result<Arc<Room>> {
+        if !is_valid_room_name(name) {
+            bail!("Invalid room name {:?}", name)
+        }
+
+        let mut rooms = self.rooms.lock().await;
+        let room = rooms
+            .entry(name.to_string())
+            .or_insert_with(|| Arc::new(Room::new()))
+            .clone();
+        room.add_user(username, sender)?;
+        Ok(room)
+    }
+
+    /// Removes `username` from the room called `name` and returns the room so the
+    /// remaining members can be notified. Empty rooms other than the default are dropped.
+    pub async fn part(&self, name: &str, username: &String) -> Option<Arc<Room>> {
+        let mut rooms = self.rooms.lock().await;
+        let room = rooms.get(name)?.clone();
+        room.remove_user(username);
+        if name != DEFAULT_ROOM && room.is_empty() {
+            rooms.remove(name);
+        }
+        Some(room)
+    }
+
+    pub async fn get(&self, name: &str) -> Option<Arc<Room>> {
+        self.rooms.lock().await.get(name).cloned()
+    }
+
+    /// Returns the names of all rooms, sorted.
+    pub async fn list(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.rooms.lock().await.keys().cloned().collect();
+        names.sort();
+        names
+    }
+
+    /// Returns every room with its members, sorted by room name.
+    pub async fn memberships(&self) -> Vec<(String, Vec<String>)> {
+        let mut rooms: Vec<(String, Vec<String>)> = self
+            .rooms
+            .lock()
+            .await
+            .iter()
+            .map(|(name, room)| (name.clone(), room.members()))
+            .collect();
+        rooms.sort();
+        rooms
+    }
+}
+
+fn is_valid_room_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_alphanumeric() || c == '-' || c == '_' || c == '.')
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::{DEFAULT_ROOM, RoomRegistry};
+    use crate::queue::{SlowConsumerPolicy, channel};
+
+    #[tokio::test]
+    async fn default_room_exists() {
+        let registry = RoomRegistry::new();
+        assert_eq!(registry.list().await, vec![DEFAULT_ROOM.to_string()]);
+    }
+
+    #[tokio::test]
+    async fn join_creates_room_on_demand() {
+        let registry = RoomRegistry::new();
+        let (tx, _rx) = channel(16, SlowConsumerPolicy::default());
+
+        registry
+            .join("project", "alice".to_string(), tx)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            registry.list().await,
+            vec![DEFAULT_ROOM.to_string(), "project".to_string()]
+        );
+    }
+
+    #[tokio::test]
+    async fn memberships_list_every_room() {
+        let registry = RoomRegistry::new();
+        let (tx, _rx) = channel(16, SlowConsumerPolicy::default());
+
+        registry
+            .join("project", "bob".to_string(), tx.clone())
+            .await
+            .unwrap();
+        registry
+            .join("project", "alice".to_string(), tx)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            registry.memberships().await,
+            vec![
+                (DEFAULT_ROOM.to_string(), vec![]),
+                (
+                    "project".to_string(),
+                    vec!["alice".to_string(), "bob".to_string()]
+                ),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn invalid_room_name_rejected() {
+        let registry = RoomRegistry::new();
+        let (tx, _rx) = channel(16, SlowConsumerPolicy::default());
+
+        let result = registry.join("a|b", "alice".to_string(), tx).await;
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn empty_room_dropped_after_part() {
+        let registry = RoomRegistry::new();
+        let (tx, _rx) = channel(16, SlowConsumerPolicy::default());
+
+        registry
+            .join("project", "alice".to_string(), tx.clone())
+            .await
+            .unwrap();
+        registry
+            .join(DEFAULT_ROOM, "alice".to_string(), tx)
+            .await
+            .unwrap();
+        registry.part("project", &"alice".to_string()).await;
+        registry.part(DEFAULT_ROOM, &"alice".to_string()).await;
+
+        assert_eq!(registry.list().await, vec![DEFAULT_ROOM.to_string()]);
+    }
+}
@@ -1,51 +1,116 @@
+use crate::queue::ClientSender;
 use anyhow::{Result, bail};
-use std::collections::HashMap;
-use tokio::sync::{Mutex, mpsc::UnboundedSender};
+use dashmap::{DashMap, Entry};
+use std::sync::{Arc, RwLock};
+use utils::{codec::Frame, message::Message};
 
 pub struct Room {
-    clients: Mutex<HashMap<String, UnboundedSender<String>>>,
+    clients: DashMap<String, ClientSender>,
+    /// Held exclusively while a member is renamed and shared by everything else that goes
+    /// by the members' names, so nobody sees a member under neither name or both.
+    renaming: RwLock<()>,
+}
+
+impl Default for Room {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Room {
     pub fn new() -> Self {
         Room {
-            clients: Mutex::new(HashMap::new()),
+            clients: DashMap::new(),
+            renaming: RwLock::new(()),
+        }
+    }
+
+    /// Adds `username` unless a member with that name exists. The check and the insert
+    /// happen under one shard lock, so concurrent logins cannot both claim a name.
+    pub fn add_user(&self, username: String, sender: ClientSender) -> Result<()> {
+        let _names = self.renaming.read().unwrap();
+        match self.clients.entry(username) {
+            Entry::Occupied(_) => bail!("Username not available!"),
+            Entry::Vacant(entry) => {
+                entry.insert(sender);
+                Ok(())
+            }
         }
     }
 
-    pub async fn add_user(&self, username: String, sender: UnboundedSender<String>) -> Result<()> {
-        let is_user_exists = self.clients.lock().await.contains_key(&username);
-        if is_user_exists {
+    /// Points `username` at a new connection, e.g. when a dropped session is resumed.
+    pub fn replace_user(&self, username: String, sender: ClientSender) {
+        let _names = self.renaming.read().unwrap();
+        self.clients.insert(username, sender);
+    }
+
+    /// Moves the member `old` to the name `new`, unless a member with that name exists.
+    /// Nothing else reaches the members meanwhile, so a concurrent broadcast finds the
+    /// member under exactly one of its names.
+    pub fn rename_user(&self, old: &String, new: String) -> Result<()> {
+        let _names = self.renaming.write().unwrap();
+        if !self.clients.contains_key(old) {
+            bail!("User with this name {} does not exists", old)
+        }
+        if *old == new {
+            return Ok(());
+        }
+        if self.clients.contains_key(&new) {
             bail!("Username not available!")
-        } else {
-            self.clients.lock().await.insert(username, sender);
-            Ok(())
         }
+        let Some((_, sender)) = self.clients.remove(old) else {
+            bail!("User with this name {} does not exists", old)
+        };
+        self.clients.insert(new, sender);
+        Ok(())
     }
 
-    pub async fn send(&self, username: &String, message: String) {
-        if let Some(sender) = self.clients.lock().await.get(username) {
-            let _ = sender.send(message);
-        } else {
-            eprintln!("User with this name {} does not exists", username)
+    pub fn send(&self, username: &String, message: impl Into<Message>) -> Result<()> {
+        let _names = self.renaming.read().unwrap();
+        match self.clients.get(username) {
+            Some(sender) if sender.send(message.into()).is_ok() => Ok(()),
+            _ => bail!("User with this name {} does not exists", username),
         }
     }
 
-    pub async fn broadcast_message(&self, message: String, username: &String) {
-        let mut clients = vec![];
-        self.clients.lock().await.iter().for_each(|(key, sender)| {
-            if *key != *username {
-                clients.push(sender.clone());
+    /// Queues `message` for every member except `username` and returns how many members
+    /// it was queued for. The message is wrapped in a single shared [`Frame`], so
+    /// recipients share one allocation and one encoding per wire format.
+    pub fn broadcast_message(&self, message: Message, username: &String) -> usize {
+        let frame = Arc::new(Frame::from(message));
+        let _names = self.renaming.read().unwrap();
+        let mut recipients = 0;
+        for member in self.clients.iter() {
+            if member.key() != username && member.value().send(Arc::clone(&frame)).is_ok() {
+                recipients += 1;
             }
-        });
+        }
+        recipients
+    }
+
+    /// Names of the members, sorted.
+    pub fn members(&self) -> Vec<String> {
+        let _names = self.renaming.read().unwrap();
+        let mut members: Vec<String> = self.clients.iter().map(|m| m.key().clone()).collect();
+        members.sort();
+        members
+    }
 
-        clients.iter().for_each(|sender| {
-            let _ = sender.send(message.clone());
-        });
+    pub fn remove_user(&self, username: &String) {
+        let _names = self.renaming.read().unwrap();
+        self.clients.remove(username);
     }
 
-    pub async fn remove_user(&self, username: &String) {
-        self.clients.lock().await.remove(username);
+    /// Returns how many messages are waiting in each member's queue.
+    pub fn queue_depths(&self) -> Vec<usize> {
+        self.clients
+            .iter()
+            .map(|member| member.value().len())
+            .collect()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.clients.is_empty()
     }
 }
 
@@ -53,58 +118,198 @@ impl Room {
 mod tests {
 
     use super::Room;
-    use tokio::sync::mpsc;
+    use crate::queue::{SlowConsumerPolicy, channel};
+    use std::sync::Arc;
+    use utils::message::Message;
 
     #[tokio::test]
     async fn add_user_success() {
         let room = Room::new();
-        let (sender, _) = mpsc::unbounded_channel();
-        let result = room.add_user("alice".to_string(), sender).await;
+        let (sender, _) = channel(16, SlowConsumerPolicy::default());
+        let result = room.add_user("alice".to_string(), sender);
         assert!(result.is_ok())
     }
 
     #[tokio::test]
     async fn user_already_exist() {
         let room = Room::new();
-        let (sender, _) = mpsc::unbounded_channel();
-        let _result = room.add_user("alice".to_string(), sender.clone()).await;
-        let result2 = room.add_user("alice".to_string(), sender).await;
+        let (sender, _) = channel(16, SlowConsumerPolicy::default());
+        let _result = room.add_user("alice".to_string(), sender.clone());
+        let result2 = room.add_user("alice".to_string(), sender);
         assert!(result2.is_err())
     }
 
     #[tokio::test]
     async fn remove_user() {
         let room = Room::new();
-        let (tx, _rx) = tokio::sync::mpsc::unbounded_channel();
+        let (tx, _rx) = channel(16, SlowConsumerPolicy::default());
 
-        room.add_user("alice".to_string(), tx).await.unwrap();
-        room.remove_user(&"alice".to_string()).await;
+        room.add_user("alice".to_string(), tx).unwrap();
+        room.remove_user(&"alice".to_string());
 
-        let clients = room.clients.lock().await;
-        assert!(!clients.contains_key("alice"));
+        assert!(!room.clients.contains_key("alice"));
     }
     #[tokio::test]
     async fn send_to_non_existing_user() {
         let room = Room::new();
 
-        // Should not panic
-        room.send(&"ghost".to_string(), "msg".to_string()).await;
+        let result = room.send(&"ghost".to_string(), "msg".to_string());
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn send_to_user() {
+        let room = Room::new();
+        let (tx, mut rx) = channel(16, SlowConsumerPolicy::default());
+
+        room.add_user("alice".to_string(), tx).unwrap();
+        let psst = Message::PRIVATE_MSG("bob".to_string(), "psst".to_string());
+        room.send(&"alice".to_string(), psst.clone()).unwrap();
+
+        assert_eq!(rx.recv().await.unwrap().message(), &psst);
     }
 
     #[tokio::test]
     async fn broadcast_message() {
         let room = Room::new();
 
-        let (tx1, mut rx1) = tokio::sync::mpsc::unbounded_channel();
-        let (tx2, mut rx2) = tokio::sync::mpsc::unbounded_channel();
+        let (tx1, mut rx1) = channel(16, SlowConsumerPolicy::default());
+        let (tx2, mut rx2) = channel(16, SlowConsumerPolicy::default());
+
+        room.add_user("alice".to_string(), tx1).unwrap();
+        room.add_user("bob".to_string(), tx2).unwrap();
+
+        let hi = Message::MSG("alice".to_string(), "hi".to_string());
+        room.broadcast_message(hi.clone(), &"alice".to_string());
+
+        assert_eq!(rx2.recv().await.unwrap().message(), &hi);
+        assert!(rx1.try_recv().is_none());
+    }
+
+    #[tokio::test]
+    async fn rename_user_to_free_name() {
+        let room = Room::new();
+        let (tx, _rx) = channel(16, SlowConsumerPolicy::default());
+
+        room.add_user("alice".to_string(), tx.clone()).unwrap();
+        room.add_user("bob".to_string(), tx).unwrap();
+
+        assert!(
+            room.rename_user(&"alice".to_string(), "bob".to_string())
+                .is_err()
+        );
+        room.rename_user(&"alice".to_string(), "alicia".to_string())
+            .unwrap();
+        assert_eq!(
+            room.members(),
+            vec!["alicia".to_string(), "bob".to_string()]
+        );
+    }
+
+    #[tokio::test]
+    async fn rename_user_to_own_name() {
+        let room = Room::new();
+        let (tx, _rx) = channel(16, SlowConsumerPolicy::default());
+
+        room.add_user("alice".to_string(), tx).unwrap();
+
+        room.rename_user(&"alice".to_string(), "alice".to_string())
+            .unwrap();
+        assert_eq!(room.members(), vec!["alice".to_string()]);
+    }
+
+    #[tokio::test]
+    async fn members_sorted() {
+        let room = Room::new();
+        let (tx, _rx) = channel(16, SlowConsumerPolicy::default());
+
+        room.add_user("bob".to_string(), tx.clone()).unwrap();
+        room.add_user("alice".to_string(), tx).unwrap();
+
+        assert_eq!(room.members(), vec!["alice".to_string(), "bob".to_string()]);
+    }
+
+    #[tokio::test]
+    async fn queue_depths_of_slow_members() {
+        let room = Room::new();
+        let (tx1, _rx1) = channel(1, SlowConsumerPolicy::DropOldest);
+        let (tx2, _rx2) = channel(16, SlowConsumerPolicy::DropOldest);
+
+        room.add_user("alice".to_string(), tx1).unwrap();
+        room.add_user("bob".to_string(), tx2).unwrap();
+        for text in ["1", "2", "3"] {
+            let msg = Message::MSG("carol".to_string(), text.to_string());
+            room.broadcast_message(msg, &"carol".to_string());
+        }
 
-        room.add_user("alice".to_string(), tx1).await.unwrap();
-        room.add_user("bob".to_string(), tx2).await.unwrap();
+        let mut depths = room.queue_depths();
+        depths.sort();
+        assert_eq!(depths, vec![1, 3]);
+    }
 
-        room.broadcast_message("hi".to_string(), &"alice".to_string())
-            .await;
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_add_user_admits_one() {
+        let room = Arc::new(Room::new());
+        let (sender, _rx) = channel(16, SlowConsumerPolicy::default());
 
-        assert_eq!(rx2.recv().await.unwrap(), "hi");
-        assert!(rx1.try_recv().is_err());
+        let attempts: Vec<_> = (0..32)
+            .map(|_| {
+                let room = Arc::clone(&room);
+                let sender = sender.clone();
+                tokio::spawn(async move { room.add_user("alice".to_string(), sender) })
+            })
+            .collect();
+        let mut admitted = 0;
+        for attempt in attempts {
+            if attempt.await.unwrap().is_ok() {
+                admitted += 1;
+            }
+        }
+
+        assert_eq!(admitted, 1);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_rename_delivers_once() {
+        let room = Arc::new(Room::new());
+        let (alice, mut rx) = channel(16 * 1024, SlowConsumerPolicy::default());
+        room.add_user("alice".to_string(), alice).unwrap();
+
+        let renames = {
+            let room = Arc::clone(&room);
+            tokio::spawn(async move {
+                for _ in 0..5000 {
+                    room.rename_user(&"alice".to_string(), "alicia".to_string())
+                        .unwrap();
+                    room.rename_user(&"alicia".to_string(), "alice".to_string())
+                        .unwrap();
+                }
+            })
+        };
+        let broadcasts: Vec<_> = (0..3)
+            .map(|sender| {
+                let room = Arc::clone(&room);
+                tokio::spawn(async move {
+                    for i in 0..2000 {
+                        let msg = Message::MSG("bob".to_string(), format!("{}-{}", sender, i));
+                        room.broadcast_message(msg, &"bob".to_string());
+                    }
+                })
+            })
+            .collect();
+        renames.await.unwrap();
+        for broadcast in broadcasts {
+            broadcast.await.unwrap();
+        }
+
+        let mut received = Vec::new();
+        while let Some(frame) = rx.try_recv() {
+            received.push(frame.message().to_string());
+        }
+        let count = received.len();
+        received.sort();
+        received.dedup();
+        assert_eq!(received.len(), count);
+        assert_eq!(count, 3 * 2000);
     }
 }
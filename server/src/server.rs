@@ -1,54 +1,460 @@
-use crate::room::Room;
+use crate::{
+    config::ServerConfig,
+    connection::{Connection, ConnectionInfo, Connections, Registration},
+    credentials::{CredentialError, CredentialStore, InMemoryCredentialStore, is_valid_username},
+    history::{HistoryStore, InMemoryHistoryStore},
+    metrics::Metrics,
+    queue::{self, ClientReceiver, ClientSender},
+    ratelimit::RateLimiter,
+    registry::{DEFAULT_ROOM, RoomRegistry},
+    room::Room,
+    session::{Parked, Sessions},
+    websocket,
+};
 use anyhow::{Result, bail};
-use futures::{SinkExt, StreamExt, stream::SplitStream};
+use dashmap::DashMap;
+use futures::{Sink, SinkExt, Stream, StreamExt};
+use std::{mem, net::SocketAddr, sync::Arc, time::Duration};
 use tokio::{
-    net::TcpStream,
-    sync::mpsc::{self, UnboundedSender},
+    io::{AsyncRead, AsyncWrite},
+    sync::watch,
+    task::JoinHandle,
+    time::{self, Instant, MissedTickBehavior},
+};
+use tokio_util::{codec::Framed, sync::CancellationToken, task::TaskTracker};
+use utils::{
+    codec::{Frame, Measured, MessageCodec, WireFormat},
+    message::Message,
+    protocol::{self, CAP_ESCAPED_FRAMES, CAP_RESUME, LEGACY_PROTOCOL_VERSION, PROTOCOL_VERSION},
 };
-use tokio_util::codec::{Framed, LinesCodec};
-use utils::message::Message;
+
+/// How long a `RESUME` waits for a still-connected session to let go.
+const RESUME_WAIT: Duration = Duration::from_secs(2);
+
+/// How long a login waits for a user holding its name as a nickname to give it back.
+const RECLAIM_WAIT: Duration = Duration::from_secs(2);
+
+/// How long a dropped connection's writer gets to stop before it is aborted.
+const WRITER_STOP_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How often [`drain`] checks whether a queue has been written out.
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Outcome of a successful login.
+struct Login {
+    username: String,
+    /// Account the user authenticated as; `username` changes with `NICK`, this does not.
+    account: String,
+    room: String,
+    /// Whether an earlier session was taken back rather than a new one started.
+    resumed: bool,
+    /// Token of a resumable session and the signal to give it up to a new connection.
+    session: Option<(String, CancellationToken)>,
+}
 
 pub struct ServerChat {
-    room: Room,
+    /// Every authenticated user, regardless of room. Keeps usernames unique server-wide.
+    users: Room,
+    rooms: RoomRegistry,
+    credentials: Arc<dyn CredentialStore>,
+    history: Arc<dyn HistoryStore>,
+    config: ServerConfig,
+    /// Holds the `SHUTDOWN` notice once [`ServerChat::close`] is called.
+    shutdown: watch::Sender<Option<Message>>,
+    /// Per-connection writer tasks, awaited on shutdown so queued frames are delivered.
+    writers: TaskTracker,
+    sessions: Sessions,
+    connections: Connections,
+    started: Instant,
+    metrics: Metrics,
+    limiter: RateLimiter,
+    /// Signals to users known by a nickname that is not their account's name, by that
+    /// nickname, to give it back once someone registers it and logs in.
+    reclaims: DashMap<String, CancellationToken>,
+}
+
+impl Default for ServerChat {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl ServerChat {
     pub fn new() -> Self {
-        Self { room: Room::new() }
+        Self::with_config(
+            ServerConfig::default(),
+            Arc::new(InMemoryCredentialStore::new()),
+            Arc::new(InMemoryHistoryStore::new()),
+        )
+    }
+
+    pub fn with_config(
+        config: ServerConfig,
+        credentials: Arc<dyn CredentialStore>,
+        history: Arc<dyn HistoryStore>,
+    ) -> Self {
+        let limiter = RateLimiter::new(config.rate_limit.clone());
+        Self {
+            users: Room::new(),
+            rooms: RoomRegistry::new(),
+            credentials,
+            history,
+            config,
+            shutdown: watch::Sender::new(None),
+            writers: TaskTracker::new(),
+            sessions: Sessions::new(),
+            connections: Connections::new(),
+            started: Instant::now(),
+            metrics: Metrics::new(),
+            limiter,
+            reclaims: DashMap::new(),
+        }
+    }
+
+    /// Serves one client connected from `peer` over any byte stream, e.g. a plain
+    /// `TcpStream` or a TLS session, speaking `format` on the wire.
+    pub async fn new_connection<S>(
+        &self,
+        stream: S,
+        peer: SocketAddr,
+        format: WireFormat,
+    ) -> Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let registration = self.accept(peer);
+        let stream = registration.meter(stream);
+        let codec = Measured::new(MessageCodec::with_max_length(
+            format,
+            self.config.max_line_length,
+        ));
+        let (writer, reader) = Framed::new(stream, codec).split();
+        self.serve_client(registration, format, writer, reader)
+            .await
+    }
+
+    /// Serves one browser connected from `peer` over a WebSocket, one message per text
+    /// frame in `format`. The WebSocket handshake must finish within the idle timeout.
+    pub async fn new_websocket_connection<S>(
+        &self,
+        stream: S,
+        peer: SocketAddr,
+        format: WireFormat,
+    ) -> Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let registration = self.accept(peer);
+        let stream = registration.meter(stream);
+        let handshake = websocket::accept(stream, format, self.config.max_line_length);
+        let Ok(accepted) = time::timeout(self.config.idle_timeout, handshake).await else {
+            tracing::warn!(
+                "Dropping {}: no WebSocket handshake within {:?}",
+                peer,
+                self.config.idle_timeout
+            );
+            bail!("WebSocket handshake timed out")
+        };
+        let (writer, reader) = accepted?;
+        self.serve_client(registration, format, writer, reader)
+            .await
     }
 
-    pub async fn new_connection(&self, stream: TcpStream) -> Result<()> {
-        let (sender, receiver) = mpsc::unbounded_channel();
-        let framed = Framed::new(stream, LinesCodec::new());
+    /// Registers a newly accepted connection.
+    fn accept(&self, peer: SocketAddr) -> Registration {
+        self.metrics.accepted.inc();
+        self.connections.open(peer)
+    }
 
-        let (mut writer, mut reader) = framed.split();
+    /// Runs a connection once its transport is set up: `writer` takes the frames for the
+    /// client in `format` and `reader` yields what it sent with the size it took on the
+    /// wire, ending when it disconnects. The connection stays listed in
+    /// [`ServerChat::connections`] until then.
+    async fn serve_client<W, R, E>(
+        &self,
+        registration: Registration,
+        format: WireFormat,
+        mut writer: W,
+        reader: R,
+    ) -> Result<()>
+    where
+        W: Sink<Arc<Frame>> + Unpin + Send + 'static,
+        R: Stream<Item = Result<(Message, usize), E>> + Unpin,
+    {
+        let received = registration.connection();
+        let messages_in = self.metrics.messages_in.clone();
+        let mut reader = reader.inspect(move |_| {
+            received.received_message();
+            messages_in.inc();
+        });
+        let sent = registration.connection();
+        let messages_out = self.metrics.messages_out.clone();
+        let (sender, receiver) = queue::counted_channel(
+            self.config.client_queue_capacity,
+            self.config.slow_consumer_policy,
+            self.metrics.dropped.clone(),
+        );
+        registration.watch_queue(sender.stats());
 
-        tokio::spawn(async move {
+        let mut shutdown = self.shutdown.subscribe();
+        let stop = CancellationToken::new();
+        let writer_stop = stop.clone();
+        let writer = self.writers.spawn(async move {
             let mut receiver = receiver;
-            while let Some(message) = receiver.recv().await {
-                if writer.send(message).await.is_err() {
-                    break;
+            // Checked per frame: the client may not have negotiated yet.
+            let readable = |frame| match format {
+                WireFormat::Pipe if !sent.supports(CAP_ESCAPED_FRAMES) => plain(frame),
+                _ => frame,
+            };
+            loop {
+                tokio::select! {
+                    message = receiver.recv() => match message {
+                        Some(message) => {
+                            if writer.send(readable(message)).await.is_err() {
+                                break;
+                            }
+                            sent.sent_message();
+                            messages_out.inc();
+                        }
+                        None => break,
+                    },
+                    () = writer_stop.cancelled() => break,
+                    Ok(()) = shutdown.changed() => {
+                        // Deliver what was already queued, then the notice.
+                        while let Some(message) = receiver.try_recv() {
+                            if writer.send(readable(message)).await.is_err() {
+                                break;
+                            }
+                            sent.sent_message();
+                            messages_out.inc();
+                        }
+                        let notice = shutdown.borrow().clone();
+                        if let Some(notice) = notice {
+                            let _ = writer.send(readable(notice.into())).await;
+                        }
+                        break;
+                    }
                 }
             }
+            let _ = writer.close().await;
+            // Handed back so a dropped session keeps collecting messages until resumed.
+            receiver
         });
 
-        let auth_username = self.authenticate_user(&mut reader, sender).await?;
+        let login = self
+            .authenticate_user(&mut reader, &registration, sender.clone())
+            .await
+            .inspect_err(|_| self.metrics.auth_failures.inc())?;
+        let mut auth_username = login.username;
+        let account = login.account;
+        let mut current_room = login.room;
+        registration.seat(&auth_username, &current_room);
+        self.send_roster(&current_room, &sender).await;
+        if !login.resumed {
+            self.replay_history(&current_room, 1, &sender);
+        }
+        let takeover = login
+            .session
+            .as_ref()
+            .map(|(_, takeover)| takeover.clone())
+            .unwrap_or_default();
+        // Cleared when the session must not outlive this connection.
+        let mut resumable = true;
+        let mut strikes = self.limiter.strikes();
+        let mut reclaim = self.reclaim_signal(&auth_username, &account);
+        // Names this connection went by before a rename its client may not have seen yet.
+        // Frames still carrying one of them were sent by this connection, not by someone
+        // impersonating it; they are forgotten once the client uses its current name.
+        let mut former_names: Vec<String> = Vec::new();
+
+        let period = self.config.heartbeat_interval;
+        let mut heartbeat = time::interval_at(Instant::now() + period, period);
+        heartbeat.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        let mut last_seen = Instant::now();
+
+        loop {
+            let message = tokio::select! {
+                message = reader.next() => message,
+                () = sender.closed() => {
+                    tracing::warn!("Disconnecting {}: fell too far behind", auth_username);
+                    resumable = false;
+                    None
+                }
+                () = takeover.cancelled() => {
+                    tracing::info!("{} is resuming from another connection", auth_username);
+                    None
+                }
+                () = reclaim.cancelled() => {
+                    self.reclaims.remove(&auth_username);
+                    reclaim = CancellationToken::new();
+                    let session = login.session.as_ref().map(|(token, _)| token.as_str());
+                    match self
+                        .rename_user(&auth_username, &account, &account, &current_room, session)
+                        .await
+                    {
+                        Ok(()) => {
+                            former_names.push(mem::replace(&mut auth_username, account.clone()));
+                            registration.seat(&auth_username, &current_room);
+                            continue;
+                        }
+                        Err(_) => {
+                            tracing::warn!(
+                                "Disconnecting {}: the name's owner logged in",
+                                auth_username
+                            );
+                            resumable = false;
+                            None
+                        }
+                    }
+                }
+                _ = heartbeat.tick() => {
+                    if last_seen.elapsed() >= self.config.idle_timeout {
+                        tracing::warn!("Disconnecting {}: idle timeout", auth_username);
+                        None
+                    } else {
+                        let _ = sender.send(Message::PING);
+                        continue;
+                    }
+                }
+            };
+            let Some(Ok((message, size))) = message else {
+                break;
+            };
+            last_seen = Instant::now();
+
+            if is_rate_limited(&message)
+                && !self.limiter.check(&account, registration.peer().ip(), size)
+            {
+                self.metrics.rate_limited.inc();
+                let _ = sender.send(Message::RATE_LIMITED);
+                if strikes.strike() {
+                    tracing::warn!(
+                        "Disconnecting {}: kept exceeding the rate limit",
+                        auth_username
+                    );
+                    drain(&sender).await;
+                    resumable = false;
+                    break;
+                }
+                continue;
+            }
+
+            if is_too_large(&message, self.config.max_text_length) {
+                tracing::warn!("Dropping an oversized frame from {}", auth_username);
+                let _ = sender.send(Message::TOO_LARGE);
+                continue;
+            }
+
+            if let Some(claimed) = claimed_username(&message)
+                && !claimed.is_empty()
+            {
+                if *claimed == auth_username {
+                    former_names.clear();
+                } else if !former_names.contains(claimed) {
+                    tracing::warn!("{} tried to send a frame as {}", auth_username, claimed);
+                    let _ = sender.send(Message::IMPERSONATION(claimed.clone()));
+                    continue;
+                }
+            }
 
-        while let Some(Ok(line)) = reader.next().await {
-            match Message::from(line) {
-                Message::MSG(username, msg) => {
-                    self.room
-                        .broadcast_message(
-                            Message::MSG(username.clone(), msg).to_string(),
-                            &username,
-                        )
-                        .await;
+            match message {
+                Message::MSG(_, msg) => {
+                    let message = Message::MSG(auth_username.clone(), msg);
+                    if let Err(e) = self.history.append(&current_room, &message) {
+                        tracing::error!("Failed to record history for {}: {}", current_room, e);
+                    }
+                    if let Some(room) = self.rooms.get(&current_room).await {
+                        self.broadcast(&room, message, &auth_username);
+                    }
+                }
+                Message::LEAVE(_) => {
+                    resumable = false;
+                    break;
+                }
+                Message::JOIN_ROOM(_, name) => {
+                    if name == current_room {
+                        continue;
+                    }
+                    match self
+                        .move_user(&auth_username, &sender, &current_room, &name)
+                        .await
+                    {
+                        Ok(()) => {
+                            let _ = sender
+                                .send(Message::JOIN_ROOM(auth_username.clone(), name.clone()));
+                            current_room = name;
+                            registration.seat(&auth_username, &current_room);
+                            self.send_roster(&current_room, &sender).await;
+                            self.replay_history(&current_room, 1, &sender);
+                        }
+                        Err(e) => {
+                            tracing::error!("{} could not join {:?}: {}", auth_username, name, e);
+                            let _ = sender.send(Message::INVALID);
+                        }
+                    }
+                }
+                Message::PART_ROOM(_, name) => {
+                    if name != current_room || name == DEFAULT_ROOM {
+                        let _ = sender.send(Message::INVALID);
+                        continue;
+                    }
+                    match self
+                        .move_user(&auth_username, &sender, &current_room, DEFAULT_ROOM)
+                        .await
+                    {
+                        Ok(()) => {
+                            let _ = sender.send(Message::PART_ROOM(auth_username.clone(), name));
+                            current_room = DEFAULT_ROOM.to_string();
+                            registration.seat(&auth_username, &current_room);
+                            self.send_roster(&current_room, &sender).await;
+                            self.replay_history(&current_room, 1, &sender);
+                        }
+                        Err(e) => {
+                            tracing::error!("{} could not part {:?}: {}", auth_username, name, e);
+                            let _ = sender.send(Message::INVALID);
+                        }
+                    }
+                }
+                Message::PRIVATE_MSG(target, msg) => {
+                    let delivered = self
+                        .users
+                        .send(&target, Message::PRIVATE_MSG(auth_username.clone(), msg));
+                    if delivered.is_err() {
+                        let _ = sender.send(Message::OFFLINE(target));
+                    }
                 }
-                Message::LEAVE(username) => {
-                    self.room.remove_user(&username).await;
-                    self.room
-                        .broadcast_message(Message::LEAVE(username.clone()).to_string(), &username)
-                        .await;
+                Message::LIST_ROOMS(_) => {
+                    let _ = sender.send(Message::LIST_ROOMS(self.rooms.list().await));
+                }
+                Message::ROSTER(_) => {
+                    self.send_roster(&current_room, &sender).await;
+                }
+                Message::NICK(_, nick) => {
+                    if nick == auth_username {
+                        continue;
+                    }
+                    let session = login.session.as_ref().map(|(token, _)| token.as_str());
+                    match self
+                        .rename_user(&auth_username, &account, &nick, &current_room, session)
+                        .await
+                    {
+                        Ok(()) => {
+                            self.reclaims.remove(&auth_username);
+                            former_names.push(mem::replace(&mut auth_username, nick));
+                            registration.seat(&auth_username, &current_room);
+                            reclaim = self.reclaim_signal(&auth_username, &account);
+                        }
+                        Err(reply) => {
+                            let _ = sender.send(reply);
+                        }
+                    }
+                }
+                Message::PING => {
+                    let _ = sender.send(Message::PONG);
+                }
+                Message::PONG => {}
+                Message::HISTORY(page) if page > 0 => {
+                    self.replay_history(&current_room, page as usize, &sender);
                 }
                 _ => {
                     tracing::error!("Invalid message");
@@ -56,54 +462,472 @@ impl ServerChat {
             }
         }
 
-        self.room.remove_user(&auth_username).await;
-        self.room
-            .broadcast_message(
-                Message::LEAVE(auth_username.clone()).to_string(),
-                &auth_username,
-            )
-            .await;
+        if sender.dropped() > 0 {
+            tracing::warn!(
+                "{} dropped {} messages while connected",
+                auth_username,
+                sender.dropped()
+            );
+        }
+
+        if auth_username != account {
+            self.reclaims.remove(&auth_username);
+        }
+        drop(registration);
+        stop.cancel();
+        if let Some((token, _)) = login.session {
+            if resumable && let Some(receiver) = stop_writer(writer).await {
+                let parked = Parked {
+                    account: account.clone(),
+                    username: auth_username.clone(),
+                    room: current_room.clone(),
+                    receiver,
+                };
+                if let Some(generation) = self.sessions.park(&token, parked) {
+                    tracing::info!(
+                        "{} disconnected; holding their session for {:?}",
+                        auth_username,
+                        self.config.resume_grace
+                    );
+                    time::sleep(self.config.resume_grace).await;
+                    if let Some(parked) = self.sessions.expire(&token, generation) {
+                        self.remove_user(&parked.username, &parked.room).await;
+                    }
+                    return Ok(());
+                }
+            }
+            self.sessions.close(&token);
+        }
+        self.remove_user(&auth_username, &current_room).await;
         Ok(())
     }
 
-    async fn authenticate_user(
+    /// Drops `username` from the server and from `room`, telling the room they left.
+    async fn remove_user(&self, username: &String, room: &str) {
+        self.users.remove_user(username);
+        if let Some(room) = self.rooms.part(room, username).await {
+            self.broadcast(&room, Message::LEAVE(username.clone()), username);
+        }
+    }
+
+    async fn authenticate_user<R, E>(
         &self,
-        reader: &mut SplitStream<Framed<TcpStream, LinesCodec>>,
-        sender: UnboundedSender<String>,
-    ) -> Result<String> {
-        if let Some(Ok(line)) = reader.next().await {
-            match Message::from(line) {
-                Message::AUTH(username) => {
-                    match self.room.add_user(username.clone(), sender.clone()).await {
-                        Err(_) => {
-                            let _ = sender.send(Message::ALREADYTAKEN.to_string());
-                            bail!("Username already taken")
-                        }
-                        _ => {
-                            self.room
-                                .broadcast_message(
-                                    Message::JOIN(username.clone()).to_string(),
-                                    &username,
-                                )
-                                .await;
-                            Ok(username)
-                        }
-                    }
-                }
-                _ => {
-                    let _ = sender.send(Message::UNAUTHENTICATED.to_string());
+        reader: &mut R,
+        connection: &Connection,
+        sender: ClientSender,
+    ) -> Result<Login>
+    where
+        R: Stream<Item = Result<(Message, usize), E>> + Unpin,
+    {
+        let Some(mut message) = self.next_login_frame(reader).await else {
+            let _ = sender.send(Message::UNAUTHENTICATED);
+            bail!("Not able to authenticate user!")
+        };
+
+        let (version, capabilities) = match message {
+            Message::HELLO(version, capabilities) => {
+                let version = self.check_protocol_version(version, &sender)?;
+                let capabilities = protocol::common_capabilities(&capabilities);
+                tracing::info!("Negotiated protocol {} with {:?}", version, capabilities);
+                connection.negotiated(capabilities.clone());
+                let _ = sender.send(Message::WELCOME(version, capabilities.clone()));
+
+                let Some(next) = self.next_login_frame(reader).await else {
+                    let _ = sender.send(Message::UNAUTHENTICATED);
                     bail!("Not able to authenticate user!")
+                };
+                message = next;
+                (version, capabilities)
+            }
+            _ => (
+                self.check_protocol_version(LEGACY_PROTOCOL_VERSION, &sender)?,
+                vec![],
+            ),
+        };
+
+        if let Message::RESUME(username, token) = message {
+            return self.resume_session(username, token, sender).await;
+        }
+
+        let (username, checked) = match message {
+            Message::AUTH(username, password) => {
+                let credentials = Arc::clone(&self.credentials);
+                let name = username.clone();
+                let checked =
+                    tokio::task::spawn_blocking(move || credentials.verify(&name, &password))
+                        .await?;
+                (username, checked)
+            }
+            Message::REGISTER(username, password) => {
+                let credentials = Arc::clone(&self.credentials);
+                let name = username.clone();
+                let checked =
+                    tokio::task::spawn_blocking(move || credentials.register(&name, &password))
+                        .await?;
+                (username, checked)
+            }
+            _ => {
+                let _ = sender.send(Message::UNAUTHENTICATED);
+                bail!("Not able to authenticate user!")
+            }
+        };
+
+        if let Err(e) = checked {
+            let reply = match e {
+                CredentialError::NoAccount => Message::NO_ACCOUNT,
+                CredentialError::BadPassword => Message::BAD_PASSWORD,
+                CredentialError::AccountExists => Message::ACCOUNT_EXISTS,
+                _ => Message::UNAUTHENTICATED,
+            };
+            let _ = sender.send(reply);
+            bail!("Not able to authenticate {}: {}", username, e)
+        }
+
+        if let Some((token, parked, takeover)) = self.sessions.take_parked(&username) {
+            return Ok(self.attach_session(token, parked, takeover, &sender).await);
+        }
+
+        match self.claim_name(&username, &sender).await {
+            Err(_) => {
+                let _ = sender.send(Message::ALREADYTAKEN);
+                bail!("Username already taken")
+            }
+            _ => {
+                let room = self
+                    .rooms
+                    .join(DEFAULT_ROOM, username.clone(), sender.clone())
+                    .await?;
+                self.broadcast(&room, Message::JOIN(username.clone()), &username);
+                tracing::info!("{} logged in using protocol {}", username, version);
+
+                let session = capabilities
+                    .iter()
+                    .any(|capability| capability == CAP_RESUME)
+                    .then(|| {
+                        let (token, takeover) = self.sessions.open(&username);
+                        let _ = sender.send(Message::SESSION(token.clone()));
+                        (token, takeover)
+                    });
+                Ok(Login {
+                    account: username.clone(),
+                    username,
+                    room: DEFAULT_ROOM.to_string(),
+                    resumed: false,
+                    session,
+                })
+            }
+        }
+    }
+
+    /// Reads the next frame of a client that has not logged in yet. Gives up after the idle
+    /// timeout, so a peer that never logs in does not hold on to its connection.
+    async fn next_login_frame<R, E>(&self, reader: &mut R) -> Option<Message>
+    where
+        R: Stream<Item = Result<(Message, usize), E>> + Unpin,
+    {
+        match time::timeout(self.config.idle_timeout, reader.next()).await {
+            Ok(Some(Ok((message, _)))) => Some(message),
+            Ok(_) => None,
+            Err(_) => {
+                tracing::warn!(
+                    "Dropping a connection that did not log in within {:?}",
+                    self.config.idle_timeout
+                );
+                None
+            }
+        }
+    }
+
+    /// Hands the session `token` of `username` to a new connection.
+    async fn resume_session(
+        &self,
+        username: String,
+        token: String,
+        sender: ClientSender,
+    ) -> Result<Login> {
+        let Some((parked, takeover)) = self.sessions.resume(&token, &username, RESUME_WAIT).await
+        else {
+            let _ = sender.send(Message::UNAUTHENTICATED);
+            bail!("{} could not resume their session", username)
+        };
+
+        Ok(self.attach_session(token, parked, takeover, &sender).await)
+    }
+
+    /// Points a held session at the connection behind `sender` and delivers the messages
+    /// queued while it was disconnected.
+    async fn attach_session(
+        &self,
+        token: String,
+        parked: Parked,
+        takeover: CancellationToken,
+        sender: &ClientSender,
+    ) -> Login {
+        let Parked {
+            account,
+            username,
+            room,
+            mut receiver,
+        } = parked;
+        self.users.replace_user(username.clone(), sender.clone());
+        if let Some(current) = self.rooms.get(&room).await {
+            current.replace_user(username.clone(), sender.clone());
+        }
+        let _ = sender.send(Message::SESSION(token.clone()));
+        while let Some(frame) = receiver.try_recv() {
+            let _ = sender.send(frame);
+        }
+        tracing::info!("{} resumed their session", username);
+
+        Login {
+            username,
+            account,
+            room,
+            resumed: true,
+            session: Some((token, takeover)),
+        }
+    }
+
+    /// Returns the version to speak with a client offering `version`, or sends
+    /// `INCOMPATIBLE` and fails if it is older than the configured minimum.
+    fn check_protocol_version(&self, version: u16, sender: &ClientSender) -> Result<u16> {
+        match protocol::negotiate_version(version, self.config.min_protocol_version) {
+            Some(version) => Ok(version),
+            None => {
+                let _ = sender.send(Message::INCOMPATIBLE(
+                    self.config.min_protocol_version,
+                    PROTOCOL_VERSION,
+                ));
+                bail!("Unsupported protocol version {}", version)
+            }
+        }
+    }
+
+    /// Renames `username`, logged in to `account`, to `nick` and tells everyone. Fails with
+    /// the reply for the client when the name is invalid or belongs to someone else.
+    async fn rename_user(
+        &self,
+        username: &String,
+        account: &str,
+        nick: &str,
+        room: &str,
+        session: Option<&str>,
+    ) -> Result<(), Message> {
+        if !is_valid_username(nick) {
+            return Err(Message::INVALID);
+        }
+        // Another account's name is reserved for its owner even while they are away.
+        if nick != account && self.credentials.exists(nick) {
+            return Err(Message::ALREADYTAKEN);
+        }
+        if self.users.rename_user(username, nick.to_string()).is_err() {
+            return Err(Message::ALREADYTAKEN);
+        }
+        if let Some(room) = self.rooms.get(room).await
+            && let Err(e) = room.rename_user(username, nick.to_string())
+        {
+            tracing::error!("Failed to rename {} in their room: {}", username, e);
+        }
+        if let Some(token) = session {
+            self.sessions.rename(token, nick);
+        }
+        tracing::info!("{} is now known as {}", username, nick);
+        let notice = Message::NICK(username.clone(), nick.to_string());
+        self.broadcast(&self.users, notice, &String::new());
+        Ok(())
+    }
+
+    /// Returns the signal for the user to give `username` back to its owner. It is only
+    /// ever sent for a nickname other than the name of the user's own `account`.
+    fn reclaim_signal(&self, username: &str, account: &str) -> CancellationToken {
+        let reclaim = CancellationToken::new();
+        if username != account {
+            self.reclaims.insert(username.to_string(), reclaim.clone());
+        }
+        reclaim
+    }
+
+    /// Adds the account `username` to the server's users. Someone using the name as a
+    /// nickname, from before the account was registered, is renamed to make room.
+    async fn claim_name(&self, username: &str, sender: &ClientSender) -> Result<()> {
+        let Some(reclaim) = self.reclaims.get(username).map(|reclaim| reclaim.clone()) else {
+            return self.users.add_user(username.to_string(), sender.clone());
+        };
+        reclaim.cancel();
+        let deadline = Instant::now() + RECLAIM_WAIT;
+        loop {
+            match self.users.add_user(username.to_string(), sender.clone()) {
+                Ok(()) => return Ok(()),
+                Err(e) if Instant::now() >= deadline => return Err(e),
+                Err(_) => time::sleep(DRAIN_POLL_INTERVAL).await,
+            }
+        }
+    }
+
+    /// Tells the client who is in `room`.
+    async fn send_roster(&self, room: &str, sender: &ClientSender) {
+        let members = match self.rooms.get(room).await {
+            Some(room) => room.members(),
+            None => vec![],
+        };
+        let _ = sender.send(Message::ROSTER(members));
+    }
+
+    /// Sends one page of `room`'s history, oldest first, as `HISTORY_MSG` frames.
+    fn replay_history(&self, room: &str, page: usize, sender: &ClientSender) {
+        match self.history.page(room, page, self.config.history_replay) {
+            Ok(messages) => {
+                for message in messages {
+                    if let Message::MSG(username, text) = message {
+                        let _ = sender.send(Message::HISTORY_MSG(username, text));
+                    }
                 }
             }
-        } else {
-            let _ = sender.send(Message::UNAUTHENTICATED.to_string());
-            bail!("Not able to authenticate user!")
+            Err(e) => tracing::error!("Failed to read history for {}: {}", room, e),
+        }
+    }
+
+    /// Moves `username` from room `from` into room `to`, announcing the change in both.
+    async fn move_user(
+        &self,
+        username: &String,
+        sender: &ClientSender,
+        from: &str,
+        to: &str,
+    ) -> Result<()> {
+        let new_room = self
+            .rooms
+            .join(to, username.clone(), sender.clone())
+            .await?;
+        if let Some(old_room) = self.rooms.part(from, username).await {
+            self.broadcast(&old_room, Message::LEAVE(username.clone()), username);
         }
+        self.broadcast(&new_room, Message::JOIN(username.clone()), username);
+        Ok(())
+    }
+
+    /// How long the server has been running.
+    pub fn uptime(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    pub fn config(&self) -> &ServerConfig {
+        &self.config
+    }
+
+    /// Names of the logged-in users, sorted, including those whose session is held for
+    /// them to resume.
+    pub fn users(&self) -> Vec<String> {
+        self.users.members()
+    }
+
+    /// Every room with its members, sorted by room name.
+    pub async fn rooms(&self) -> Vec<(String, Vec<String>)> {
+        self.rooms.memberships().await
     }
 
-    pub async fn close(&self) {
-        self.room
-            .broadcast_message("ClOSE".to_string(), &"".to_string())
-            .await;
+    /// Describes the open connections, oldest first.
+    pub fn connections(&self) -> Vec<ConnectionInfo> {
+        self.connections.list()
+    }
+
+    /// Forgets the rate limits of senders that have been quiet long enough to start
+    /// afresh. Called periodically, so the scan does not run on every disconnect.
+    pub fn prune_rate_limits(&self) {
+        self.limiter.prune();
+    }
+
+    /// The server's metrics in the Prometheus text format.
+    pub fn metrics(&self) -> String {
+        let depths = self.users.queue_depths();
+        self.metrics.queued.set(depths.iter().sum::<usize>() as i64);
+        self.metrics
+            .max_queue_depth
+            .set(depths.into_iter().max().unwrap_or(0) as i64);
+        let connections = self.connections.list();
+        self.metrics
+            .active_connections
+            .set(connections.len() as i64);
+        let lagging = connections.iter().filter(|c| c.lagging).count();
+        self.metrics.lagging.set(lagging as i64);
+        self.metrics.render()
+    }
+
+    /// Queues `message` for every member of `room` except `username`, recording the
+    /// fan-out.
+    fn broadcast(&self, room: &Room, message: Message, username: &String) {
+        let recipients = room.broadcast_message(message, username);
+        self.metrics.fanout.observe(recipients as f64);
+    }
+
+    /// Sends every connection a `SHUTDOWN` notice carrying `reason` after the frames
+    /// already queued for it, and waits up to `deadline` for them to be written.
+    /// Returns `false` if some connections could not be drained in time.
+    pub async fn close(&self, reason: Option<String>, deadline: Duration) -> bool {
+        self.shutdown.send_replace(Some(Message::SHUTDOWN(reason)));
+        self.writers.close();
+        tokio::time::timeout(deadline, self.writers.wait())
+            .await
+            .is_ok()
+    }
+}
+
+/// `frame`, or a plain copy of it if it can only be written as an escaped frame, for
+/// clients that did not agree on those.
+fn plain(frame: Arc<Frame>) -> Arc<Frame> {
+    if frame.encoded(WireFormat::Pipe).starts_with(b"\\") {
+        Arc::new(Frame::from(frame.message().to_plain()))
+    } else {
+        frame
+    }
+}
+
+/// Whether a client frame counts against the rate limits. Heartbeats and leaving are
+/// always let through.
+fn is_rate_limited(message: &Message) -> bool {
+    !matches!(message, Message::PING | Message::PONG | Message::LEAVE(_))
+}
+
+/// Whether a client frame was too long to read, or carries text longer than `max_text`.
+fn is_too_large(message: &Message, max_text: usize) -> bool {
+    match message {
+        Message::TOO_LARGE => true,
+        Message::MSG(_, text) | Message::PRIVATE_MSG(_, text) => text.len() > max_text,
+        _ => false,
+    }
+}
+
+/// Returns the username a client frame claims to be sent by, for frames that carry one.
+fn claimed_username(message: &Message) -> Option<&String> {
+    match message {
+        Message::MSG(username, _)
+        | Message::LEAVE(username)
+        | Message::JOIN_ROOM(username, _)
+        | Message::PART_ROOM(username, _)
+        | Message::NICK(username, _) => Some(username),
+        _ => None,
+    }
+}
+
+/// Gives the writer up to [`WRITER_STOP_TIMEOUT`] to take what is queued for a client
+/// about to be disconnected, so it learns why.
+async fn drain(sender: &ClientSender) {
+    let drained = async {
+        while !sender.is_empty() {
+            time::sleep(DRAIN_POLL_INTERVAL).await;
+        }
+    };
+    let _ = time::timeout(WRITER_STOP_TIMEOUT, drained).await;
+}
+
+/// Stops a connection's writer and takes back its queue, or gives up if the writer is
+/// stuck on a dead socket.
+async fn stop_writer(mut writer: JoinHandle<ClientReceiver>) -> Option<ClientReceiver> {
+    match time::timeout(WRITER_STOP_TIMEOUT, &mut writer).await {
+        Ok(receiver) => receiver.ok(),
+        Err(_) => {
+            writer.abort();
+            None
+        }
     }
 }
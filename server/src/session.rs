@@ -0,0 +1,248 @@
+use crate::queue::ClientReceiver;
+use std::{collections::HashMap, pin::pin, sync::Mutex, time::Duration};
+use tokio::{sync::Notify, time::Instant};
+use tokio_util::sync::CancellationToken;
+
+/// A disconnected user whose name, room and queued messages are being held for them.
+pub struct Parked {
+    /// Account the user logged in to, which a password login takes the session back by.
+    pub account: String,
+    /// Name the user was known by, which may differ from the account after a rename.
+    pub username: String,
+    pub room: String,
+    /// Queue of the dropped connection, still collecting the messages sent meanwhile.
+    pub receiver: ClientReceiver,
+}
+
+struct Session {
+    username: String,
+    /// Cancelled to make the live connection park the session, so it can be resumed
+    /// from a new connection.
+    takeover: CancellationToken,
+    parked: Option<Parked>,
+    /// Bumped on every park so a stale grace timer cannot expire a newer one.
+    generation: u64,
+}
+
+/// Resumable sessions, keyed by the token handed to the client in `SESSION`.
+#[derive(Default)]
+pub struct Sessions {
+    sessions: Mutex<HashMap<String, Session>>,
+    parked: Notify,
+}
+
+impl Sessions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Starts a session for `username`. Returns its token and the signal telling the
+    /// connection to park the session because it is being resumed elsewhere.
+    pub fn open(&self, username: &str) -> (String, CancellationToken) {
+        let token: String = rand::random::<[u8; 16]>()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        let takeover = CancellationToken::new();
+        self.sessions.lock().unwrap().insert(
+            token.clone(),
+            Session {
+                username: username.to_string(),
+                takeover: takeover.clone(),
+                parked: None,
+                generation: 0,
+            },
+        );
+        (token, takeover)
+    }
+
+    /// Holds `parked` until the session is resumed or expired. Returns the generation
+    /// to pass to [`Sessions::expire`].
+    pub fn park(&self, token: &str, parked: Parked) -> Option<u64> {
+        let mut sessions = self.sessions.lock().unwrap();
+        let session = sessions.get_mut(token)?;
+        session.parked = Some(parked);
+        session.generation += 1;
+        self.parked.notify_waiters();
+        Some(session.generation)
+    }
+
+    /// Takes back the session `token` of `username`. A session still attached to a live
+    /// connection is asked to park first, waiting at most `wait` for it.
+    pub async fn resume(
+        &self,
+        token: &str,
+        username: &str,
+        wait: Duration,
+    ) -> Option<(Parked, CancellationToken)> {
+        let deadline = Instant::now() + wait;
+        loop {
+            let mut notified = pin!(self.parked.notified());
+            notified.as_mut().enable();
+            {
+                let mut sessions = self.sessions.lock().unwrap();
+                let session = sessions.get_mut(token)?;
+                if session.username != username {
+                    return None;
+                }
+                if let Some(parked) = session.parked.take() {
+                    session.takeover = CancellationToken::new();
+                    return Some((parked, session.takeover.clone()));
+                }
+                session.takeover.cancel();
+            }
+            tokio::time::timeout_at(deadline, notified).await.ok()?;
+        }
+    }
+
+    /// Takes back a disconnected session of `account`, for a user who logged in again
+    /// with their password instead of the token.
+    pub fn take_parked(&self, account: &str) -> Option<(String, Parked, CancellationToken)> {
+        let mut sessions = self.sessions.lock().unwrap();
+        let (token, session) = sessions.iter_mut().find(|(_, session)| {
+            session
+                .parked
+                .as_ref()
+                .is_some_and(|parked| parked.account == account)
+        })?;
+        session.takeover = CancellationToken::new();
+        Some((
+            token.clone(),
+            session.parked.take()?,
+            session.takeover.clone(),
+        ))
+    }
+
+    /// Follows a rename, so the session is resumed under the new name.
+    pub fn rename(&self, token: &str, username: &str) {
+        if let Some(session) = self.sessions.lock().unwrap().get_mut(token) {
+            session.username = username.to_string();
+        }
+    }
+
+    /// Ends the session if it is still parked from the `generation` given by
+    /// [`Sessions::park`], returning what was held for it.
+    pub fn expire(&self, token: &str, generation: u64) -> Option<Parked> {
+        let mut sessions = self.sessions.lock().unwrap();
+        let session = sessions.get(token)?;
+        if session.generation != generation || session.parked.is_none() {
+            return None;
+        }
+        sessions.remove(token)?.parked
+    }
+
+    /// Ends the session, e.g. after the user left.
+    pub fn close(&self, token: &str) {
+        self.sessions.lock().unwrap().remove(token);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::{Parked, Sessions};
+    use crate::queue::{SlowConsumerPolicy, channel};
+    use std::{sync::Arc, time::Duration};
+
+    fn parked() -> Parked {
+        let (_tx, receiver) = channel(4, SlowConsumerPolicy::default());
+        Parked {
+            account: "alice".to_string(),
+            username: "alice".to_string(),
+            room: "general".to_string(),
+            receiver,
+        }
+    }
+
+    #[tokio::test]
+    async fn resume_parked_session() {
+        let sessions = Sessions::new();
+        let (token, _takeover) = sessions.open("alice");
+        sessions.park(&token, parked()).unwrap();
+
+        let resumed = sessions.resume(&token, "alice", Duration::ZERO).await;
+        assert_eq!(resumed.unwrap().0.room, "general");
+    }
+
+    #[tokio::test]
+    async fn resume_requires_matching_user() {
+        let sessions = Sessions::new();
+        let (token, _takeover) = sessions.open("alice");
+        sessions.park(&token, parked()).unwrap();
+
+        assert!(
+            sessions
+                .resume(&token, "mallory", Duration::ZERO)
+                .await
+                .is_none()
+        );
+        assert!(
+            sessions
+                .resume("bogus", "alice", Duration::ZERO)
+                .await
+                .is_none()
+        );
+    }
+
+    #[tokio::test]
+    async fn resume_takes_over_live_session() {
+        let sessions = Arc::new(Sessions::new());
+        let (token, takeover) = sessions.open("alice");
+
+        let live = {
+            let sessions = Arc::clone(&sessions);
+            let token = token.clone();
+            tokio::spawn(async move {
+                takeover.cancelled().await;
+                sessions.park(&token, parked());
+            })
+        };
+        let resumed = sessions
+            .resume(&token, "alice", Duration::from_secs(1))
+            .await;
+        live.await.unwrap();
+
+        assert!(resumed.is_some());
+    }
+
+    #[tokio::test]
+    async fn stale_expiry_ignored_after_resume() {
+        let sessions = Sessions::new();
+        let (token, _takeover) = sessions.open("alice");
+        let first = sessions.park(&token, parked()).unwrap();
+        sessions
+            .resume(&token, "alice", Duration::ZERO)
+            .await
+            .unwrap();
+        let second = sessions.park(&token, parked()).unwrap();
+
+        assert!(sessions.expire(&token, first).is_none());
+        assert!(sessions.expire(&token, second).is_some());
+    }
+
+    #[tokio::test]
+    async fn resume_after_rename() {
+        let sessions = Sessions::new();
+        let (token, _takeover) = sessions.open("alice");
+        sessions.rename(&token, "alicia");
+        sessions.park(&token, parked()).unwrap();
+
+        assert!(
+            sessions
+                .resume(&token, "alicia", Duration::ZERO)
+                .await
+                .is_some()
+        );
+    }
+
+    #[tokio::test]
+    async fn take_parked_by_username() {
+        let sessions = Sessions::new();
+        let (token, _takeover) = sessions.open("alice");
+        assert!(sessions.take_parked("alice").is_none());
+
+        sessions.park(&token, parked()).unwrap();
+        let (taken, _, _) = sessions.take_parked("alice").unwrap();
+        assert_eq!(taken, token);
+    }
+}
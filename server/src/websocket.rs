@@ -0,0 +1,59 @@
+use futures::{Sink, SinkExt, Stream, StreamExt, future};
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_tungstenite::tungstenite::{
+    Error, Message as WsMessage, Utf8Bytes, error::CapacityError, protocol::WebSocketConfig,
+};
+use utils::{
+    codec::{Frame, WireFormat},
+    message::Message,
+};
+
+/// Completes the WebSocket handshake on `stream` and returns its two halves: a sink taking
+/// frames to send and a stream of received messages with their size in bytes. Each
+/// message travels as one text frame in `format`; binary frames are treated as invalid
+/// messages. A WebSocket message longer than `max_length` bytes is received as
+/// `Message::TOO_LARGE`, after which the connection is closed.
+pub async fn accept<S>(
+    stream: S,
+    format: WireFormat,
+    max_length: usize,
+) -> Result<
+    (
+        impl Sink<Arc<Frame>, Error = Error> + Unpin + Send + 'static,
+        impl Stream<Item = Result<(Message, usize), Error>> + Unpin + Send,
+    ),
+    Error,
+>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let config = WebSocketConfig::default()
+        .max_message_size(Some(max_length))
+        .max_frame_size(Some(max_length));
+    let (sink, stream) = tokio_tungstenite::accept_async_with_config(stream, Some(config))
+        .await?
+        .split();
+    let writer = sink.with(move |frame: Arc<Frame>| future::ready(text(&frame, format)));
+    let reader = stream.filter_map(move |received| {
+        future::ready(match received {
+            Ok(WsMessage::Text(text)) => Some(Ok((format.decode(text.to_string()), text.len()))),
+            Ok(WsMessage::Binary(data)) => Some(Ok((Message::INVALID, data.len()))),
+            // Pings are answered by tungstenite, and a close ends the stream.
+            Ok(_) => None,
+            Err(Error::Capacity(CapacityError::MessageTooLong { size, .. })) => {
+                Some(Ok((Message::TOO_LARGE, size)))
+            }
+            Err(Error::Capacity(_)) => Some(Ok((Message::TOO_LARGE, 0))),
+            Err(e) => Some(Err(e)),
+        })
+    });
+    Ok((writer, reader))
+}
+
+/// The text frame carrying `frame`, sharing its cached encoding minus the line terminator.
+fn text(frame: &Frame, format: WireFormat) -> Result<WsMessage, Error> {
+    let line = frame.encoded(format);
+    let text = Utf8Bytes::try_from(line.slice(..line.len() - 1))?;
+    Ok(WsMessage::Text(text))
+}
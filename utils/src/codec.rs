@@ -0,0 +1,282 @@
+use crate::message::Message;
+use std::{
+    mem,
+    sync::{Arc, OnceLock},
+};
+use tokio_util::{
+    bytes::{Bytes, BytesMut},
+    codec::{Decoder, Encoder, LinesCodec, LinesCodecError},
+};
+
+/// How a [`Message`] is written on a single line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum WireFormat {
+    /// The `username|type|text` format.
+    #[default]
+    Pipe,
+    /// One JSON object per line, e.g. `{"type":"MSG","data":["alice","hi"]}`.
+    Json,
+}
+
+impl WireFormat {
+    pub fn encode(&self, message: &Message) -> String {
+        match self {
+            WireFormat::Pipe => message.to_string(),
+            WireFormat::Json => {
+                serde_json::to_string(message).expect("Message always serializes to JSON")
+            }
+        }
+    }
+
+    /// Decodes one line, yielding `Message::INVALID` for anything unparseable.
+    pub fn decode(&self, line: String) -> Message {
+        match self {
+            WireFormat::Pipe => Message::from(line),
+            WireFormat::Json => serde_json::from_str(&line).unwrap_or(Message::INVALID),
+        }
+    }
+}
+
+/// A [`Message`] shared by every recipient of a broadcast. Each wire format is encoded
+/// at most once, on first use, and the resulting line is shared from then on.
+#[derive(Debug)]
+pub struct Frame {
+    message: Message,
+    pipe: OnceLock<Bytes>,
+    json: OnceLock<Bytes>,
+}
+
+impl Frame {
+    pub fn message(&self) -> &Message {
+        &self.message
+    }
+
+    /// Returns the message as one newline-terminated line in `format`.
+    pub fn encoded(&self, format: WireFormat) -> Bytes {
+        let cell = match format {
+            WireFormat::Pipe => &self.pipe,
+            WireFormat::Json => &self.json,
+        };
+        cell.get_or_init(|| {
+            let mut line = format.encode(&self.message);
+            line.push('\n');
+            Bytes::from(line)
+        })
+        .clone()
+    }
+}
+
+impl From<Message> for Frame {
+    fn from(message: Message) -> Self {
+        Self {
+            message,
+            pipe: OnceLock::new(),
+            json: OnceLock::new(),
+        }
+    }
+}
+
+impl From<Message> for Arc<Frame> {
+    fn from(message: Message) -> Self {
+        Arc::new(Frame::from(message))
+    }
+}
+
+/// Line-delimited [`Message`] codec for `Framed` streams.
+#[derive(Debug, Clone, Default)]
+pub struct MessageCodec {
+    lines: LinesCodec,
+    format: WireFormat,
+}
+
+impl MessageCodec {
+    pub fn new(format: WireFormat) -> Self {
+        Self {
+            lines: LinesCodec::new(),
+            format,
+        }
+    }
+
+    /// Like [`MessageCodec::new`], but a line longer than `max_length` bytes is skipped
+    /// without being buffered and decoded as `Message::TOO_LARGE`.
+    pub fn with_max_length(format: WireFormat, max_length: usize) -> Self {
+        Self {
+            lines: LinesCodec::new_with_max_length(max_length),
+            format,
+        }
+    }
+
+    fn message(
+        &self,
+        line: Result<Option<String>, LinesCodecError>,
+    ) -> Result<Option<Message>, LinesCodecError> {
+        match line {
+            Ok(line) => Ok(line.map(|line| self.format.decode(line))),
+            Err(LinesCodecError::MaxLineLengthExceeded) => Ok(Some(Message::TOO_LARGE)),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+impl Decoder for MessageCodec {
+    type Item = Message;
+    type Error = LinesCodecError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Message>, LinesCodecError> {
+        let line = self.lines.decode(src);
+        self.message(line)
+    }
+
+    fn decode_eof(&mut self, src: &mut BytesMut) -> Result<Option<Message>, LinesCodecError> {
+        let line = self.lines.decode_eof(src);
+        self.message(line)
+    }
+}
+
+impl Encoder<Message> for MessageCodec {
+    type Error = LinesCodecError;
+
+    fn encode(&mut self, message: Message, dst: &mut BytesMut) -> Result<(), LinesCodecError> {
+        self.lines.encode(self.format.encode(&message), dst)
+    }
+}
+
+impl Encoder<Arc<Frame>> for MessageCodec {
+    type Error = LinesCodecError;
+
+    fn encode(&mut self, frame: Arc<Frame>, dst: &mut BytesMut) -> Result<(), LinesCodecError> {
+        dst.extend_from_slice(&frame.encoded(self.format));
+        Ok(())
+    }
+}
+
+/// Wraps a codec so that each decoded item comes with the number of bytes it took on
+/// the wire, including any that were skipped on the way to it.
+#[derive(Debug, Clone, Default)]
+pub struct Measured<C> {
+    inner: C,
+    pending: usize,
+}
+
+impl<C> Measured<C> {
+    pub fn new(inner: C) -> Self {
+        Self { inner, pending: 0 }
+    }
+}
+
+impl<C: Decoder> Decoder for Measured<C> {
+    type Item = (C::Item, usize);
+    type Error = C::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, C::Error> {
+        let before = src.len();
+        let item = self.inner.decode(src);
+        self.pending += before - src.len();
+        Ok(item?.map(|item| (item, mem::take(&mut self.pending))))
+    }
+
+    fn decode_eof(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, C::Error> {
+        let before = src.len();
+        let item = self.inner.decode_eof(src);
+        self.pending += before - src.len();
+        Ok(item?.map(|item| (item, mem::take(&mut self.pending))))
+    }
+}
+
+impl<T, C: Encoder<T>> Encoder<T> for Measured<C> {
+    type Error = C::Error;
+
+    fn encode(&mut self, item: T, dst: &mut BytesMut) -> Result<(), C::Error> {
+        self.inner.encode(item, dst)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn json_round_trip() {
+        let msg = Message::MSG("alice".to_string(), "a | b\nc".to_string());
+        let encoded = WireFormat::Json.encode(&msg);
+
+        assert_eq!(encoded, r#"{"type":"MSG","data":["alice","a | b\nc"]}"#);
+        assert_eq!(WireFormat::Json.decode(encoded), msg);
+    }
+
+    #[test]
+    fn json_unit_variant() {
+        let msg = WireFormat::Json.decode(r#"{"type":"ALREADYTAKEN"}"#.to_string());
+
+        assert_eq!(msg, Message::ALREADYTAKEN);
+    }
+
+    #[test]
+    fn invalid_json() {
+        let msg = WireFormat::Json.decode("alice|2|hi".to_string());
+
+        assert_eq!(msg, Message::INVALID);
+    }
+
+    #[test]
+    fn codec_frames_lines() {
+        let mut codec = MessageCodec::new(WireFormat::Json);
+        let mut buffer = BytesMut::new();
+        codec
+            .encode(Message::JOIN("bob".to_string()), &mut buffer)
+            .unwrap();
+
+        assert_eq!(&buffer[..], b"{\"type\":\"JOIN\",\"data\":\"bob\"}\n");
+        assert_eq!(
+            codec.decode(&mut buffer).unwrap(),
+            Some(Message::JOIN("bob".to_string()))
+        );
+    }
+
+    #[test]
+    fn overlong_line_skipped() {
+        let mut codec = MessageCodec::with_max_length(WireFormat::Pipe, 16);
+        let mut buffer = BytesMut::from(&b"alice|2|this line is far too long"[..]);
+
+        assert_eq!(codec.decode(&mut buffer).unwrap(), Some(Message::TOO_LARGE));
+        buffer.extend_from_slice(b" still going\nalice|2|hi\n");
+        assert_eq!(
+            codec.decode(&mut buffer).unwrap(),
+            Some(Message::MSG("alice".to_string(), "hi".to_string()))
+        );
+        assert_eq!(codec.decode(&mut buffer).unwrap(), None);
+    }
+
+    #[test]
+    fn frame_encoded_once_per_format() {
+        let frame = Frame::from(Message::JOIN("bob".to_string()));
+        let pipe = frame.encoded(WireFormat::Pipe);
+
+        assert_eq!(&pipe[..], b"bob|3|\n");
+        assert_eq!(frame.encoded(WireFormat::Pipe).as_ptr(), pipe.as_ptr());
+        assert_eq!(
+            &frame.encoded(WireFormat::Json)[..],
+            b"{\"type\":\"JOIN\",\"data\":\"bob\"}\n"
+        );
+    }
+
+    #[test]
+    fn measured_counts_lines_and_skipped_bytes() {
+        let mut codec = Measured::new(MessageCodec::with_max_length(WireFormat::Pipe, 8));
+        let mut buffer = BytesMut::from(&b"bob|3|\r\nthis line is too long\nbob|4|\n"[..]);
+
+        assert_eq!(
+            codec.decode(&mut buffer).unwrap(),
+            Some((Message::JOIN("bob".to_string()), 8))
+        );
+        // The overlong line is only skipped while looking for the next one
+        assert_eq!(
+            codec.decode(&mut buffer).unwrap(),
+            Some((Message::TOO_LARGE, 0))
+        );
+        assert_eq!(
+            codec.decode(&mut buffer).unwrap(),
+            Some((Message::LEAVE("bob".to_string()), 29))
+        );
+    }
+}
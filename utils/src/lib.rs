@@ -1 +1,4 @@
+pub mod codec;
 pub mod message;
+pub mod protocol;
+pub mod tls;
@@ -1,5 +1,12 @@
+use serde::{Deserialize, Serialize};
+use std::{borrow::Cow, fmt};
+
 type Username = String;
 type Text = String;
+type RoomName = String;
+type Password = String;
+type ProtocolVersion = u16;
+type Capability = String;
 
 const AUTH: u16 = 1;
 const MSG: u16 = 2;
@@ -8,31 +15,135 @@ const LEAVE: u16 = 4;
 const INVALID: u16 = 5;
 const ALREADYTAKEN: u16 = 6;
 const UNAUTHENTICATED: u16 = 7;
-
+const JOIN_ROOM: u16 = 8;
+const PART_ROOM: u16 = 9;
+const LIST_ROOMS: u16 = 10;
+const PRIVATE_MSG: u16 = 11;
+const OFFLINE: u16 = 12;
+const IMPERSONATION: u16 = 13;
+const REGISTER: u16 = 14;
+const NO_ACCOUNT: u16 = 15;
+const BAD_PASSWORD: u16 = 16;
+const ACCOUNT_EXISTS: u16 = 17;
+const HELLO: u16 = 18;
+const WELCOME: u16 = 19;
+const INCOMPATIBLE: u16 = 20;
+const HISTORY: u16 = 21;
+const HISTORY_MSG: u16 = 22;
+const SHUTDOWN: u16 = 23;
+const PING: u16 = 24;
+const PONG: u16 = 25;
+const SESSION: u16 = 26;
+const RESUME: u16 = 27;
+const ROSTER: u16 = 28;
+const NICK: u16 = 29;
+const RATE_LIMITED: u16 = 30;
+const TOO_LARGE: u16 = 31;
+
+/// Separator used between room names in a `LIST_ROOMS` reply, usernames in a `ROSTER`
+/// reply and between capabilities.
+const ROOM_SEPARATOR: char = ',';
+
+/// Separates the protocol version from what follows it in handshake frames.
+const VERSION_SEPARATOR: char = ' ';
+
+/// Leading character of frames whose fields are escaped. Frames without it use the
+/// original unescaped format, where no field may contain `|` or a line break.
+const ESCAPED_MARKER: char = '\\';
+
+#[allow(non_camel_case_types)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", content = "data")]
 pub enum Message {
-    AUTH(Username),
+    /// Log in to an existing account.
+    AUTH(Username, Password),
     MSG(Username, Text),
     JOIN(Username),
     LEAVE(Username),
     ALREADYTAKEN,
     UNAUTHENTICATED,
     INVALID,
+    /// Enter a named room, creating it on demand. Echoed back to the sender on success.
+    JOIN_ROOM(Username, RoomName),
+    /// Leave a named room and return to the default one. Echoed back to the sender on success.
+    PART_ROOM(Username, RoomName),
+    /// Empty when sent by a client, the sorted room names when sent by the server.
+    LIST_ROOMS(Vec<RoomName>),
+    /// Direct message. Carries the recipient when sent by a client and the sender when
+    /// delivered by the server.
+    PRIVATE_MSG(Username, Text),
+    /// The target of a `PRIVATE_MSG` is not connected.
+    OFFLINE(Username),
+    /// A frame claimed to come from a user other than the authenticated one and was dropped.
+    IMPERSONATION(Username),
+    /// Create an account and log in to it.
+    REGISTER(Username, Password),
+    /// `AUTH` named an account that does not exist.
+    NO_ACCOUNT,
+    /// `AUTH` gave the wrong password.
+    BAD_PASSWORD,
+    /// `REGISTER` named an account that already exists.
+    ACCOUNT_EXISTS,
+    /// Optional first frame from a client: its protocol version and capabilities.
+    HELLO(ProtocolVersion, Vec<Capability>),
+    /// Reply to `HELLO`: the negotiated version and the capabilities both sides support.
+    WELCOME(ProtocolVersion, Vec<Capability>),
+    /// The client's protocol version is not supported. Carries the lowest and highest
+    /// versions the server accepts.
+    INCOMPATIBLE(ProtocolVersion, ProtocolVersion),
+    /// Request for a page of the current room's history, 1 being the most recent.
+    HISTORY(u32),
+    /// A message from the room's history, replayed on join or in reply to `HISTORY`.
+    HISTORY_MSG(Username, Text),
+    /// The server is shutting down, optionally saying why.
+    SHUTDOWN(Option<Text>),
+    /// Liveness probe; the peer answers with `PONG`.
+    PING,
+    PONG,
+    /// Token the client can send in `RESUME` to take its session back after a dropped
+    /// connection.
+    SESSION(Text),
+    /// Login frame resuming a session with the token from `SESSION`, in place of `AUTH`.
+    RESUME(Username, Text),
+    /// Empty when sent by a client, the sorted members of its current room when sent by
+    /// the server. The server also sends it after login and after every room change.
+    ROSTER(Vec<Username>),
+    /// Rename from the first username to the second. Broadcast to everyone once the
+    /// server accepted it; a name that is taken is answered with `ALREADYTAKEN`.
+    NICK(Username, Username),
+    /// A frame was dropped because its sender exceeded the server's rate limit. Clients
+    /// that keep sending are disconnected.
+    RATE_LIMITED,
+    /// A frame or the text in it was longer than the receiver accepts and was dropped.
+    /// [`MessageCodec`](crate::codec::MessageCodec) also decodes an overlong line as this.
+    TOO_LARGE,
 }
 
 impl Message {
     pub fn from(input: String) -> Self {
-        let parts: Vec<&str> = input.split('|').collect();
-
+        let escaped = input.starts_with(ESCAPED_MARKER);
+        let parts: Vec<&str> = input
+            .strip_prefix(ESCAPED_MARKER)
+            .unwrap_or(&input)
+            .split('|')
+            .collect();
         if parts.len() != 3 {
             return Message::INVALID;
         }
-
-        let username = parts[0].to_string();
-        let msg_type = parts[1].parse::<u16>();
-        let text = parts[2].to_string();
+        // Lists are split before their items are unescaped, so they also need `raw`.
+        let (msg_type, raw) = (parts[1], parts[2]);
+        let (username, text) = if escaped {
+            match (unescape(parts[0]), unescape(raw)) {
+                (Some(username), Some(text)) => (username, text),
+                _ => return Message::INVALID,
+            }
+        } else {
+            (parts[0].to_string(), raw.to_string())
+        };
+        let msg_type = msg_type.parse::<u16>();
 
         match msg_type {
-            Ok(AUTH) => Message::AUTH(username),
+            Ok(AUTH) => Message::AUTH(username, text),
 
             Ok(JOIN) => Message::JOIN(username),
 
@@ -44,50 +155,324 @@ impl Message {
 
             Ok(UNAUTHENTICATED) => Message::UNAUTHENTICATED,
 
+            Ok(JOIN_ROOM) => Message::JOIN_ROOM(username, text),
+
+            Ok(PART_ROOM) => Message::PART_ROOM(username, text),
+
+            Ok(LIST_ROOMS) => match parse_list(raw, escaped) {
+                Some(rooms) => Message::LIST_ROOMS(rooms),
+                None => Message::INVALID,
+            },
+
+            Ok(PRIVATE_MSG) => Message::PRIVATE_MSG(username, text),
+
+            Ok(OFFLINE) => Message::OFFLINE(username),
+
+            Ok(IMPERSONATION) => Message::IMPERSONATION(username),
+
+            Ok(REGISTER) => Message::REGISTER(username, text),
+
+            Ok(NO_ACCOUNT) => Message::NO_ACCOUNT,
+
+            Ok(BAD_PASSWORD) => Message::BAD_PASSWORD,
+
+            Ok(ACCOUNT_EXISTS) => Message::ACCOUNT_EXISTS,
+
+            Ok(HELLO) => match parse_handshake(raw, escaped) {
+                Some((version, capabilities)) => Message::HELLO(version, capabilities),
+                None => Message::INVALID,
+            },
+
+            Ok(WELCOME) => match parse_handshake(raw, escaped) {
+                Some((version, capabilities)) => Message::WELCOME(version, capabilities),
+                None => Message::INVALID,
+            },
+
+            Ok(INCOMPATIBLE) => match text
+                .split_once(VERSION_SEPARATOR)
+                .and_then(|(min, max)| Some((min.parse().ok()?, max.parse().ok()?)))
+            {
+                Some((min, max)) => Message::INCOMPATIBLE(min, max),
+                None => Message::INVALID,
+            },
+
+            Ok(HISTORY) => match text.parse() {
+                Ok(page) => Message::HISTORY(page),
+                Err(_) => Message::INVALID,
+            },
+
+            Ok(HISTORY_MSG) => Message::HISTORY_MSG(username, text),
+
+            // Escaped frames always carry a reason, which is how an empty one is told
+            // apart from none.
+            Ok(SHUTDOWN) => Message::SHUTDOWN((escaped || !text.is_empty()).then_some(text)),
+
+            Ok(PING) => Message::PING,
+
+            Ok(PONG) => Message::PONG,
+
+            Ok(SESSION) => Message::SESSION(text),
+
+            Ok(RESUME) => Message::RESUME(username, text),
+
+            Ok(NICK) => Message::NICK(username, text),
+
+            Ok(RATE_LIMITED) => Message::RATE_LIMITED,
+
+            Ok(TOO_LARGE) => Message::TOO_LARGE,
+
+            Ok(ROSTER) => match parse_list(raw, escaped) {
+                Some(members) => Message::ROSTER(members),
+                None => Message::INVALID,
+            },
+
             _ => Message::INVALID,
         }
     }
-}
 
-impl Message {
-    pub fn to_string(self) -> String {
+    /// Splits the message into its `username`, type and `text` wire fields.
+    fn fields(&self) -> (&str, u16, Cow<'_, str>) {
         match self {
-            Message::AUTH(username) => {
-                format!("{}|{}|", username, AUTH)
-            }
-            Message::JOIN(username) => {
-                format!("{}|{}|", username, JOIN)
-            }
-            Message::LEAVE(username) => {
-                format!("{}|{}|", username, LEAVE)
-            }
-            Message::MSG(username, text) => {
-                format!("{}|{}|{}", username, MSG, text)
-            }
-            Message::ALREADYTAKEN => {
-                format!("|{}|", ALREADYTAKEN)
-            }
-            Message::UNAUTHENTICATED => {
-                format!("|{}|", UNAUTHENTICATED)
-            }
-            Message::INVALID => {
-                format!("|{}|", INVALID)
+            Message::AUTH(username, password) => (username, AUTH, Cow::from(password)),
+            Message::JOIN(username) => (username, JOIN, Cow::from("")),
+            Message::LEAVE(username) => (username, LEAVE, Cow::from("")),
+            Message::MSG(username, text) => (username, MSG, Cow::from(text)),
+            Message::ALREADYTAKEN => ("", ALREADYTAKEN, Cow::from("")),
+            Message::UNAUTHENTICATED => ("", UNAUTHENTICATED, Cow::from("")),
+            Message::INVALID => ("", INVALID, Cow::from("")),
+            Message::JOIN_ROOM(username, room) => (username, JOIN_ROOM, Cow::from(room)),
+            Message::PART_ROOM(username, room) => (username, PART_ROOM, Cow::from(room)),
+            Message::LIST_ROOMS(rooms) => (
+                "",
+                LIST_ROOMS,
+                Cow::from(rooms.join(&ROOM_SEPARATOR.to_string())),
+            ),
+            Message::PRIVATE_MSG(username, text) => (username, PRIVATE_MSG, Cow::from(text)),
+            Message::OFFLINE(username) => (username, OFFLINE, Cow::from("")),
+            Message::IMPERSONATION(username) => (username, IMPERSONATION, Cow::from("")),
+            Message::REGISTER(username, password) => (username, REGISTER, Cow::from(password)),
+            Message::NO_ACCOUNT => ("", NO_ACCOUNT, Cow::from("")),
+            Message::BAD_PASSWORD => ("", BAD_PASSWORD, Cow::from("")),
+            Message::ACCOUNT_EXISTS => ("", ACCOUNT_EXISTS, Cow::from("")),
+            Message::HELLO(version, capabilities) => (
+                "",
+                HELLO,
+                Cow::from(format_handshake(*version, capabilities)),
+            ),
+            Message::WELCOME(version, capabilities) => (
+                "",
+                WELCOME,
+                Cow::from(format_handshake(*version, capabilities)),
+            ),
+            Message::INCOMPATIBLE(min, max) => (
+                "",
+                INCOMPATIBLE,
+                Cow::from(format!("{}{}{}", min, VERSION_SEPARATOR, max)),
+            ),
+            Message::HISTORY(page) => ("", HISTORY, Cow::from(page.to_string())),
+            Message::HISTORY_MSG(username, text) => (username, HISTORY_MSG, Cow::from(text)),
+            Message::SHUTDOWN(reason) => (
+                "",
+                SHUTDOWN,
+                Cow::from(reason.as_deref().unwrap_or_default()),
+            ),
+            Message::PING => ("", PING, Cow::from("")),
+            Message::PONG => ("", PONG, Cow::from("")),
+            Message::SESSION(token) => ("", SESSION, Cow::from(token)),
+            Message::RESUME(username, token) => (username, RESUME, Cow::from(token)),
+            Message::NICK(username, nick) => (username, NICK, Cow::from(nick)),
+            Message::RATE_LIMITED => ("", RATE_LIMITED, Cow::from("")),
+            Message::TOO_LARGE => ("", TOO_LARGE, Cow::from("")),
+            Message::ROSTER(members) => (
+                "",
+                ROSTER,
+                Cow::from(members.join(&ROOM_SEPARATOR.to_string())),
+            ),
+        }
+    }
+
+    /// A copy that fits the plain format, for peers that cannot read escaped frames.
+    /// This loses information: pipes become `¦`, line breaks become spaces, leading
+    /// backslashes of the username are dropped and so are empty list items.
+    pub fn to_plain(&self) -> Message {
+        let (username, msg_type, text) = self.fields();
+        let username = username.trim_start_matches(ESCAPED_MARKER);
+        Message::from(format!(
+            "{}|{}|{}",
+            plain_field(username),
+            msg_type,
+            plain_field(&text)
+        ))
+    }
+
+    /// The protocol version, for handshakes, and the items of messages that carry a
+    /// list.
+    fn list(&self) -> Option<(Option<ProtocolVersion>, &[String])> {
+        match self {
+            Message::LIST_ROOMS(items) | Message::ROSTER(items) => Some((None, items)),
+            Message::HELLO(version, items) | Message::WELCOME(version, items) => {
+                Some((Some(*version), items))
             }
+            _ => None,
+        }
+    }
+
+    /// Whether the plain format tells this message apart from every other one. An
+    /// empty shutdown reason would read back as none, and plain lists cannot hold empty
+    /// items or items containing the separator.
+    fn fits_plain_format(&self) -> bool {
+        match self {
+            Message::SHUTDOWN(Some(reason)) => !reason.is_empty(),
+            message => message.list().is_none_or(|(_, items)| {
+                items
+                    .iter()
+                    .all(|item| !item.is_empty() && !item.contains(ROOM_SEPARATOR))
+            }),
+        }
+    }
+
+    /// The `text` field of an escaped frame. List items are escaped one by one, with
+    /// the separator escaped too, and each is followed by a separator.
+    fn escaped_text(&self, text: &str) -> String {
+        let Some((version, items)) = self.list() else {
+            return escape(text);
+        };
+        let mut escaped = match version {
+            Some(version) => format!("{}{}", version, VERSION_SEPARATOR),
+            None => String::new(),
+        };
+        for item in items {
+            escaped.push_str(&escape(item).replace(ROOM_SEPARATOR, "\\c"));
+            escaped.push(ROOM_SEPARATOR);
+        }
+        escaped
+    }
+}
+
+impl From<String> for Message {
+    /// Parses a line in the pipe format, like [`Message::from`].
+    fn from(line: String) -> Self {
+        Message::from(line)
+    }
+}
+
+impl fmt::Display for Message {
+    /// Writes the plain `username|type|text` format when it can carry the fields
+    /// unchanged, so older peers keep understanding ordinary messages. Otherwise the
+    /// frame is prefixed with `ESCAPED_MARKER` and its fields are escaped.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (username, msg_type, text) = self.fields();
+        if needs_escaping(username)
+            || needs_escaping(&text)
+            || username.starts_with(ESCAPED_MARKER)
+            || !self.fits_plain_format()
+        {
+            write!(
+                f,
+                "{}{}|{}|{}",
+                ESCAPED_MARKER,
+                escape(username),
+                msg_type,
+                self.escaped_text(&text)
+            )
+        } else {
+            write!(f, "{}|{}|{}", username, msg_type, text)
+        }
+    }
+}
+
+fn format_handshake(version: ProtocolVersion, capabilities: &[Capability]) -> String {
+    format!(
+        "{}{}{}",
+        version,
+        VERSION_SEPARATOR,
+        capabilities.join(&ROOM_SEPARATOR.to_string())
+    )
+}
+
+fn parse_handshake(raw: &str, escaped: bool) -> Option<(ProtocolVersion, Vec<Capability>)> {
+    let (version, capabilities) = raw.split_once(VERSION_SEPARATOR).unwrap_or((raw, ""));
+    Some((version.parse().ok()?, parse_list(capabilities, escaped)?))
+}
+
+/// Parses the items of a list field as it was on the wire. In escaped frames every
+/// item ends with a separator; `None` if one does not, or cannot be unescaped.
+fn parse_list(raw: &str, escaped: bool) -> Option<Vec<String>> {
+    if !escaped {
+        return Some(
+            raw.split(ROOM_SEPARATOR)
+                .filter(|item| !item.is_empty())
+                .map(str::to_string)
+                .collect(),
+        );
+    }
+    if raw.is_empty() {
+        return Some(vec![]);
+    }
+    raw.strip_suffix(ROOM_SEPARATOR)?
+        .split(ROOM_SEPARATOR)
+        .map(unescape)
+        .collect()
+}
+
+fn needs_escaping(field: &str) -> bool {
+    field.contains(['|', '\n', '\r'])
+}
+
+fn plain_field(field: &str) -> String {
+    field.replace('|', "¦").replace(['\n', '\r'], " ")
+}
+
+fn escape(field: &str) -> String {
+    let mut escaped = String::with_capacity(field.len());
+    for c in field.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '|' => escaped.push_str("\\p"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Reverses `escape`, returning `None` for unknown or truncated escape sequences.
+fn unescape(field: &str) -> Option<String> {
+    let mut unescaped = String::with_capacity(field.len());
+    let mut chars = field.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next()? {
+            '\\' => unescaped.push('\\'),
+            'p' => unescaped.push('|'),
+            'n' => unescaped.push('\n'),
+            'r' => unescaped.push('\r'),
+            'c' => unescaped.push(ROOM_SEPARATOR),
+            _ => return None,
         }
     }
+    Some(unescaped)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use proptest::prelude::*;
 
     #[test]
     fn auth_message() {
-        let input = String::from("alice|1|");
+        let input = String::from("alice|1|hunter2");
         let msg = Message::from(input);
 
         match msg {
-            Message::AUTH(username) => assert_eq!(username, "alice"),
+            Message::AUTH(username, password) => {
+                assert_eq!(username, "alice");
+                assert_eq!(password, "hunter2");
+            }
             _ => panic!("Expected AUTH message"),
         }
     }
@@ -162,10 +547,10 @@ mod tests {
 
     #[test]
     fn to_string_auth() {
-        let msg = Message::AUTH("alice".to_string());
+        let msg = Message::AUTH("alice".to_string(), "hunter2".to_string());
         let encoded = msg.to_string();
 
-        assert_eq!(encoded, "alice|1|");
+        assert_eq!(encoded, "alice|1|hunter2");
     }
 
     #[test]
@@ -176,4 +561,210 @@ mod tests {
 
         assert_eq!(encoded, original);
     }
+
+    #[test]
+    fn join_room_message() {
+        let msg = Message::from(String::from("alice|8|project"));
+
+        assert_eq!(
+            msg,
+            Message::JOIN_ROOM("alice".to_string(), "project".to_string())
+        );
+    }
+
+    #[test]
+    fn list_rooms_request_is_empty() {
+        let msg = Message::from(String::from("|10|"));
+
+        assert_eq!(msg, Message::LIST_ROOMS(vec![]));
+    }
+
+    #[test]
+    fn round_trip_roster() {
+        let original = String::from("|28|alice,bob");
+        let msg = Message::from(original.clone());
+
+        assert_eq!(
+            msg,
+            Message::ROSTER(vec!["alice".to_string(), "bob".to_string()])
+        );
+        assert_eq!(msg.to_string(), original);
+    }
+
+    #[test]
+    fn round_trip_list_rooms() {
+        let original = String::from("|10|general,project");
+        let msg = Message::from(original.clone());
+
+        assert_eq!(
+            msg,
+            Message::LIST_ROOMS(vec!["general".to_string(), "project".to_string()])
+        );
+        assert_eq!(msg.to_string(), original);
+    }
+
+    #[test]
+    fn round_trip_private_msg() {
+        let original = String::from("bob|11|see you at 5");
+        let msg = Message::from(original.clone());
+
+        assert_eq!(
+            msg,
+            Message::PRIVATE_MSG("bob".to_string(), "see you at 5".to_string())
+        );
+        assert_eq!(msg.to_string(), original);
+    }
+
+    #[test]
+    fn register_message() {
+        let msg = Message::from(String::from("alice|14|hunter2"));
+
+        assert_eq!(
+            msg,
+            Message::REGISTER("alice".to_string(), "hunter2".to_string())
+        );
+    }
+
+    #[test]
+    fn plain_frames_keep_original_format() {
+        let msg = Message::MSG("alice".to_string(), r"C:\temp is full".to_string());
+
+        assert_eq!(msg.to_string(), r"alice|2|C:\temp is full");
+        assert_eq!(Message::from(msg.to_string()), msg);
+    }
+
+    #[test]
+    fn pipe_and_newline_round_trip() {
+        let msg = Message::MSG(
+            "alice".to_string(),
+            "ps aux | grep chat\n| a | b |".to_string(),
+        );
+        let encoded = msg.to_string();
+
+        assert!(!encoded.contains('\n'));
+        assert_eq!(encoded.matches('|').count(), 2);
+        assert_eq!(Message::from(encoded), msg);
+    }
+
+    #[test]
+    fn invalid_escape_sequence() {
+        let msg = Message::from(String::from(r"\alice|2|bad \x escape"));
+
+        assert_eq!(msg, Message::INVALID);
+    }
+
+    #[test]
+    fn hello_message() {
+        let msg = Message::from(String::from("|18|2 rooms,private-messages"));
+
+        assert_eq!(
+            msg,
+            Message::HELLO(2, vec!["rooms".to_string(), "private-messages".to_string()])
+        );
+    }
+
+    #[test]
+    fn lists_with_empty_items_and_separators_round_trip() {
+        let rooms = Message::LIST_ROOMS(vec![String::new(), "a,b".to_string(), r"c\".to_string()]);
+        let encoded = rooms.to_string();
+
+        assert_eq!(encoded, r"\|10|,a\cb,c\\,");
+        assert_eq!(Message::from(encoded), rooms);
+
+        let hello = Message::HELLO(2, vec![String::new()]);
+        assert_eq!(Message::from(hello.to_string()), hello);
+    }
+
+    #[test]
+    fn escaped_list_without_trailing_separator() {
+        let msg = Message::from(String::from(r"\|28|alice"));
+
+        assert_eq!(msg, Message::INVALID);
+    }
+
+    #[test]
+    fn plain_copy_replaces_pipes_and_line_breaks() {
+        let msg = Message::MSG("alice".to_string(), "a | b\nc".to_string());
+
+        assert_eq!(msg.to_plain().to_string(), "alice|2|a ¦ b c");
+    }
+
+    #[test]
+    fn shutdown_without_reason() {
+        assert_eq!(Message::SHUTDOWN(None).to_string(), "|23|");
+        assert_eq!(Message::from(String::from("|23|")), Message::SHUTDOWN(None));
+    }
+
+    #[test]
+    fn shutdown_with_empty_reason() {
+        let msg = Message::SHUTDOWN(Some(String::new()));
+
+        assert_eq!(msg.to_string(), r"\|23|");
+        assert_eq!(Message::from(msg.to_string()), msg);
+    }
+
+    fn any_message() -> impl Strategy<Value = Message> {
+        // Any characters, including the separator, escapes and line breaks
+        let name = "(?s).{0,16}";
+        let text = "(?s).{0,64}";
+        let list = || prop::collection::vec(any::<String>(), 0..5);
+        prop_oneof![
+            (name, text).prop_map(|(u, t)| Message::AUTH(u, t)),
+            (name, text).prop_map(|(u, t)| Message::MSG(u, t)),
+            name.prop_map(Message::JOIN),
+            name.prop_map(Message::LEAVE),
+            Just(Message::ALREADYTAKEN),
+            Just(Message::UNAUTHENTICATED),
+            Just(Message::INVALID),
+            (name, any::<String>()).prop_map(|(u, r)| Message::JOIN_ROOM(u, r)),
+            (name, any::<String>()).prop_map(|(u, r)| Message::PART_ROOM(u, r)),
+            list().prop_map(Message::LIST_ROOMS),
+            (name, text).prop_map(|(u, t)| Message::PRIVATE_MSG(u, t)),
+            name.prop_map(Message::OFFLINE),
+            name.prop_map(Message::IMPERSONATION),
+            (name, text).prop_map(|(u, t)| Message::REGISTER(u, t)),
+            Just(Message::NO_ACCOUNT),
+            Just(Message::BAD_PASSWORD),
+            Just(Message::ACCOUNT_EXISTS),
+            (any::<u16>(), list()).prop_map(|(v, c)| Message::HELLO(v, c)),
+            (any::<u16>(), list()).prop_map(|(v, c)| Message::WELCOME(v, c)),
+            (any::<u16>(), any::<u16>()).prop_map(|(min, max)| Message::INCOMPATIBLE(min, max)),
+            any::<u32>().prop_map(Message::HISTORY),
+            (name, text).prop_map(|(u, t)| Message::HISTORY_MSG(u, t)),
+            prop::option::of(text).prop_map(Message::SHUTDOWN),
+            Just(Message::PING),
+            Just(Message::PONG),
+            text.prop_map(Message::SESSION),
+            (name, text).prop_map(|(u, t)| Message::RESUME(u, t)),
+            list().prop_map(Message::ROSTER),
+            (name, name).prop_map(|(u, n)| Message::NICK(u, n)),
+            Just(Message::RATE_LIMITED),
+            Just(Message::TOO_LARGE),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn round_trip_any_message(msg in any_message()) {
+            let encoded = msg.to_string();
+
+            prop_assert!(!encoded.contains(['\n', '\r']));
+            prop_assert_eq!(Message::from(encoded), msg);
+        }
+
+        #[test]
+        fn plain_copy_of_any_message(msg in any_message()) {
+            let plain = msg.to_plain();
+
+            prop_assert!(!plain.to_string().starts_with(ESCAPED_MARKER));
+            prop_assert_eq!(Message::from(plain.to_string()), plain);
+        }
+
+        #[test]
+        fn json_round_trip_any_message(msg in any_message()) {
+            let encoded = serde_json::to_string(&msg).unwrap();
+
+            prop_assert_eq!(serde_json::from_str::<Message>(&encoded).unwrap(), msg);
+        }
+    }
 }
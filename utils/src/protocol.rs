@@ -0,0 +1,76 @@
+/// Protocol version spoken by this build.
+pub const PROTOCOL_VERSION: u16 = 2;
+
+/// The original protocol, spoken by peers that log in without a `HELLO`.
+pub const LEGACY_PROTOCOL_VERSION: u16 = 1;
+
+/// Room every user is placed in after authentication and returned to after parting.
+pub const DEFAULT_ROOM: &str = "general";
+
+/// Longest line, in bytes, accepted from a peer unless configured otherwise.
+pub const DEFAULT_MAX_LINE_LENGTH: usize = 16 * 1024;
+
+/// Longest message text, in bytes, accepted unless configured otherwise.
+pub const DEFAULT_MAX_TEXT_LENGTH: usize = 4 * 1024;
+
+pub const CAP_ROOMS: &str = "rooms";
+pub const CAP_PRIVATE_MESSAGES: &str = "private-messages";
+pub const CAP_ESCAPED_FRAMES: &str = "escaped-frames";
+pub const CAP_RESUME: &str = "resume";
+
+/// Capabilities supported by this build.
+pub const CAPABILITIES: &[&str] = &[
+    CAP_ROOMS,
+    CAP_PRIVATE_MESSAGES,
+    CAP_ESCAPED_FRAMES,
+    CAP_RESUME,
+];
+
+/// Picks the highest version both sides speak, or `None` if that is below `min_version`.
+pub fn negotiate_version(peer_version: u16, min_version: u16) -> Option<u16> {
+    let version = peer_version.min(PROTOCOL_VERSION);
+    (version >= min_version).then_some(version)
+}
+
+/// Returns the capabilities offered by the peer that this build supports too.
+/// Unknown capabilities are ignored so newer peers can advertise more.
+pub fn common_capabilities(offered: &[String]) -> Vec<String> {
+    offered
+        .iter()
+        .filter(|capability| CAPABILITIES.contains(&capability.as_str()))
+        .cloned()
+        .collect()
+}
+
+/// Capabilities supported by this build, in wire form.
+pub fn capabilities() -> Vec<String> {
+    CAPABILITIES.iter().map(|c| c.to_string()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn newer_peer_negotiates_down() {
+        assert_eq!(
+            negotiate_version(PROTOCOL_VERSION + 3, LEGACY_PROTOCOL_VERSION),
+            Some(PROTOCOL_VERSION)
+        );
+    }
+
+    #[test]
+    fn older_peer_below_minimum_refused() {
+        assert_eq!(
+            negotiate_version(LEGACY_PROTOCOL_VERSION, PROTOCOL_VERSION),
+            None
+        );
+    }
+
+    #[test]
+    fn unknown_capabilities_ignored() {
+        let offered = vec!["teleport".to_string(), CAP_ROOMS.to_string()];
+
+        assert_eq!(common_capabilities(&offered), vec![CAP_ROOMS.to_string()]);
+    }
+}
@@ -0,0 +1,57 @@
+use anyhow::{Context, Result};
+use rustls_pki_types::{CertificateDer, PrivateKeyDer, pem::PemObject};
+use std::{path::Path, sync::Arc};
+use tokio_rustls::{
+    TlsAcceptor, TlsConnector,
+    rustls::{ClientConfig, RootCertStore, ServerConfig, server::WebPkiClientVerifier},
+};
+
+/// Reads every certificate from a PEM file.
+pub fn load_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>> {
+    let certs = CertificateDer::pem_file_iter(path)
+        .with_context(|| format!("Failed to open certificates {}", path.display()))?
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("Failed to parse certificates {}", path.display()))?;
+    anyhow::ensure!(!certs.is_empty(), "No certificates in {}", path.display());
+    Ok(certs)
+}
+
+/// Reads the first private key from a PEM file.
+pub fn load_private_key(path: &Path) -> Result<PrivateKeyDer<'static>> {
+    PrivateKeyDer::from_pem_file(path)
+        .with_context(|| format!("Failed to read private key {}", path.display()))
+}
+
+fn root_store(ca: &Path) -> Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(ca)? {
+        roots.add(cert)?;
+    }
+    Ok(roots)
+}
+
+/// Builds a server-side acceptor. Clients must present a certificate signed by
+/// `client_ca` when one is given.
+pub fn acceptor(cert: &Path, key: &Path, client_ca: Option<&Path>) -> Result<TlsAcceptor> {
+    let builder = match client_ca {
+        Some(ca) => ServerConfig::builder().with_client_cert_verifier(
+            WebPkiClientVerifier::builder(Arc::new(root_store(ca)?)).build()?,
+        ),
+        None => ServerConfig::builder().with_no_client_auth(),
+    };
+    let config = builder.with_single_cert(load_certs(cert)?, load_private_key(key)?)?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// Builds a client-side connector trusting the certificates in `ca`, optionally
+/// authenticating with a client certificate and key.
+pub fn connector(ca: &Path, identity: Option<(&Path, &Path)>) -> Result<TlsConnector> {
+    let builder = ClientConfig::builder().with_root_certificates(root_store(ca)?);
+    let config = match identity {
+        Some((cert, key)) => {
+            builder.with_client_auth_cert(load_certs(cert)?, load_private_key(key)?)?
+        }
+        None => builder.with_no_client_auth(),
+    };
+    Ok(TlsConnector::from(Arc::new(config)))
+}